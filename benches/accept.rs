@@ -0,0 +1,141 @@
+#![feature(test)]
+
+extern crate test;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate rux;
+
+use rux::{RawFd, Reset};
+use rux::epoll::*;
+use rux::handler::*;
+use rux::mux::*;
+use rux::prop::Prop;
+use rux::prop::reuseport::{AcceptMode, Steering};
+use rux::prop::server::*;
+use rux::prop::signals::SigSet;
+use rux::sys::socket::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use test::Bencher;
+
+const IO_THREADS: usize = 4;
+const CLIENTS: usize = 4;
+const CONNECTIONS: usize = 64;
+
+#[derive(Clone)]
+struct Buf;
+
+impl Reset for Buf {
+  fn reset(&mut self) {}
+}
+
+struct Echo {
+  closed: bool,
+}
+
+impl<'a> Handler<MuxEvent<'a, Buf>, MuxCmd> for Echo {
+  fn next(&mut self) -> MuxCmd {
+    if self.closed {
+      return MuxCmd::Close;
+    }
+
+    MuxCmd::Keep
+  }
+
+  fn on_next(&mut self, event: MuxEvent<'a, Buf>) {
+    if event.events.intersects(EPOLLRDHUP | EPOLLHUP) {
+      self.closed = true;
+      return;
+    }
+
+    let mut buf = [0; 64];
+    if let Ok(Some(n)) = syscall!(recv(event.fd, &mut buf, MSG_DONTWAIT)) {
+      send(event.fd, &buf[..n], MSG_DONTWAIT).unwrap();
+    }
+  }
+}
+
+impl EpollHandler for Echo {
+  fn interests() -> EpollEventKind {
+    EPOLLIN | EPOLLRDHUP | EPOLLET
+  }
+
+  fn with_epfd(&mut self, _: EpollFd) {}
+}
+
+#[derive(Clone)]
+struct EchoFactory;
+
+impl<'a> HandlerFactory<'a, Echo, Buf> for EchoFactory {
+  fn new_handler(&mut self, _: EpollFd, _: RawFd) -> Echo {
+    Echo { closed: false }
+  }
+
+  fn new_resource(&self) -> Buf {
+    Buf
+  }
+}
+
+/// Connect, echo a byte and close `CONNECTIONS` times from each of `CLIENTS` threads.
+fn bench_accept(b: &mut Bencher, port: u16, accept_mode: AcceptMode, backend: Backend) {
+  let config = ServerConfig::tcp(("127.0.0.1", port))
+    .unwrap()
+    .io_threads(IO_THREADS)
+    .accept_mode(accept_mode)
+    .backend(backend);
+
+  let mut server = Server::new(config, EchoFactory).unwrap();
+  let mut epoll = server.setup(SigSet::empty()).unwrap();
+  let main = thread::spawn(move || epoll.run());
+
+  b.iter(|| {
+    let clients: Vec<_> = (0..CLIENTS)
+      .map(|_| {
+        thread::spawn(move || for _ in 0..CONNECTIONS {
+          let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+          stream.write_all(b"x").unwrap();
+          stream.read_exact(&mut [0]).unwrap();
+        })
+      })
+      .collect();
+
+    for client in clients {
+      client.join().unwrap();
+    }
+  });
+
+  server.shutdown();
+  main.join().unwrap().unwrap();
+}
+
+#[bench]
+pub fn bench_accept_shared(b: &mut Bencher) {
+  bench_accept(b, 9801, AcceptMode::Shared, Backend::Epoll);
+}
+
+#[bench]
+pub fn bench_accept_reuseport_hash(b: &mut Bencher) {
+  bench_accept(b, 9802, AcceptMode::ReusePort(Steering::Hash), Backend::Epoll);
+}
+
+#[bench]
+pub fn bench_accept_reuseport_incoming_cpu(b: &mut Bencher) {
+  bench_accept(b, 9803, AcceptMode::ReusePort(Steering::IncomingCpu), Backend::Epoll);
+}
+
+#[bench]
+pub fn bench_accept_reuseport_cbpf(b: &mut Bencher) {
+  bench_accept(b, 9804, AcceptMode::ReusePort(Steering::Cbpf), Backend::Epoll);
+}
+
+#[bench]
+pub fn bench_accept_shared_uring(b: &mut Bencher) {
+  bench_accept(b, 9805, AcceptMode::Shared, Backend::Uring);
+}
+
+#[bench]
+pub fn bench_accept_reuseport_cbpf_uring(b: &mut Bencher) {
+  bench_accept(b, 9806, AcceptMode::ReusePort(Steering::Cbpf), Backend::Uring);
+}
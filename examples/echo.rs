@@ -110,15 +110,21 @@ fn main() {
         EPOLL_LOOP_MS,
         MAX_CONN);
 
+  // `echo uring` runs the loops on io_uring
+  let backend = match ::std::env::args().nth(1).as_ref().map(|a| a.as_str()) {
+    Some("uring") => Backend::Uring,
+    _ => Backend::Epoll,
+  };
+
   let config = ServerConfig::tcp(("127.0.0.1", 9999))
     .unwrap()
     .max_conn(MAX_CONN)
     .io_threads(1)
     // .io_threads(::std::cmp::max(1, ::num_cpus::get() / 2))
-    .epoll_config(EpollConfig {
-      loop_ms: EPOLL_LOOP_MS,
-      buffer_capacity: EPOLL_BUF_CAP,
-    });
+    .epoll_config(EpollConfig::default()
+      .loop_ms(EPOLL_LOOP_MS)
+      .buffer_capacity(EPOLL_BUF_CAP)
+      .backend(backend));
 
   let server = Server::new(config, EchoFactory).unwrap();
 
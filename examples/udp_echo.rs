@@ -0,0 +1,44 @@
+#[macro_use]
+extern crate log;
+extern crate rux;
+extern crate env_logger;
+
+use rux::handler::*;
+use rux::datagram::*;
+use rux::epoll::*;
+use rux::prop::server::*;
+use rux::daemon::*;
+
+const EPOLL_BUF_CAP: usize = 2048;
+const EPOLL_LOOP_MS: isize = -1;
+
+/// Handler that echoes incoming datagrams back to their sender
+#[derive(Clone, Debug)]
+pub struct EchoHandler;
+
+impl<'d> Handler<Datagram<'d>, ()> for EchoHandler {
+
+  fn next(&mut self) {}
+
+  fn on_next(&mut self, dgram: Datagram<'d>) {
+    if let Err(e) = dgram.replies.send(&dgram.peer, dgram.data) {
+      warn!("dropping reply to {}: {}", dgram.peer, e);
+    }
+  }
+}
+
+fn main() {
+
+  ::env_logger::init().unwrap();
+
+  let config = ServerConfig::udp(("127.0.0.1", 9999))
+    .unwrap()
+    .io_threads(1)
+    .epoll_config(EpollConfig::default().loop_ms(EPOLL_LOOP_MS).buffer_capacity(EPOLL_BUF_CAP));
+
+  let server = Server::new_with(config,
+                                |epfd| SyncDatagram::new(Default::default(), epfd, EchoHandler))
+    .unwrap();
+
+  Daemon::build(server).run().unwrap();
+}
@@ -1,4 +1,4 @@
-use daemon::{Daemon, DaemonCmd};
+use daemon::{Daemon, DaemonCmd, Notifier};
 use error::Result;
 use handler::Handler;
 use libc_sys::{c_int, sched_param, sched_get_priority_max};
@@ -6,6 +6,7 @@ use nix::Errno;
 use nix::sys::signal::{Signal, SigSet};
 use prop::{Prop, Reload};
 use prop::signals::DefaultSigHandler;
+use std::path::{Path, PathBuf};
 
 #[allow(non_camel_case_types)]
 pub type sched_policy = c_int;
@@ -15,6 +16,8 @@ pub struct DaemonBuilder<S, P> {
   sig_h: S,
   sig_mask: SigSet,
   sched_opt: Option<(sched_policy, sched_param)>,
+  notifier: Option<Notifier>,
+  handoff: Option<PathBuf>,
 }
 
 impl<S, P> DaemonBuilder<S, P>
@@ -37,8 +40,27 @@ impl<S, P> DaemonBuilder<S, P>
     DaemonBuilder { sched_opt: Some((policy, sched_param_i)), ..self }
   }
 
+  /// Notify the service manager through `notifier` instead of the
+  /// one described by `NOTIFY_SOCKET`, if any.
+  pub fn with_notifier(self, notifier: Notifier) -> DaemonBuilder<S, P> {
+    DaemonBuilder { notifier: Some(notifier), ..self }
+  }
+
+  /// Hand off the prop's listening sockets to a new process that connects to
+  /// `path`, e.g. a new version configured with `ServerConfig::handoff(path)`,
+  /// then shut down once it accepts on them.
+  pub fn with_handoff<T: AsRef<Path>>(self, path: T) -> DaemonBuilder<S, P> {
+    DaemonBuilder { handoff: Some(path.as_ref().to_owned()), ..self }
+  }
+
   pub fn run(self) -> Result<()> {
-    Daemon::run(self.prop, self.sig_h, self.sig_mask, self.sched_opt)
+    let notifier = match self.notifier {
+      Some(notifier) => Some(notifier),
+      None => Notifier::from_env()?,
+    };
+
+    Daemon::run(self.prop, self.sig_h, self.sig_mask, self.sched_opt, notifier,
+                self.handoff)
   }
 }
 
@@ -55,6 +77,8 @@ impl<P> Daemon<DefaultSigHandler, P>
       sig_h: DefaultSigHandler::new(),
       prop: prop,
       sched_opt: None,
+      notifier: None,
+      handoff: None,
     }
   }
 }
@@ -6,11 +6,19 @@ pub use nix::sys::signal::{Signal, SigSet};
 use nix::sys::signalfd::{SignalFd, SFD_NONBLOCK};
 use nix::{unistd, Errno};
 use prop::*;
+use prop::handoff::{self, Handoff, Successor};
+use prop::threads::PanicFd;
 use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use timer::{ClockId, TimerFd, TimerSpec};
 
 mod builder;
+mod notify;
 
 pub use self::builder::sched_policy;
+pub use self::notify::Notifier;
 pub use libc_sys::{SCHED_FIFO, SCHED_RR, SCHED_OTHER};
 
 /// WIP: New-style daemon
@@ -19,20 +27,33 @@ pub use libc_sys::{SCHED_FIFO, SCHED_RR, SCHED_OTHER};
 /// Missing:
 /// - Force exposing daemon interface via D-Bus along with a D-Bus service activation
 ///   configuration file
-/// - Force Prop to optionally be socket activatable
-/// - Optionally notify init system via http://man7.org/linux/man-pages/man3/sd_notify.3.html
 pub struct Daemon<S, P> {
+  epfd: EpollFd,
   sigfd: SignalFd,
   sig_h: S,
   prop: P,
+  notifier: Option<Notifier>,
+  watchdog: Option<TimerFd>,
+  handoff: Option<Handoff>,
+  successor: Option<Pending>,
+  panics: Option<Arc<PanicFd>>,
+  panicked: bool,
   terminating: bool
 }
 
+/// Handoff to a successor waiting for its reply, given up on when `deadline` fires.
+struct Pending {
+  successor: Successor,
+  path: PathBuf,
+  deadline: TimerFd,
+}
+
 impl<S, P> Daemon<S, P>
   where S: Handler<Signal, DaemonCmd> + 'static,
         P: Prop + Reload + Send + 'static,
 {
-  pub fn run(mut prop: P, sig_h: S, sig_mask: SigSet, sched_opt: Option<(sched_policy, sched_param)>) -> Result<()> {
+  pub fn run(mut prop: P, sig_h: S, sig_mask: SigSet, sched_opt: Option<(sched_policy, sched_param)>,
+             notifier: Option<Notifier>, handoff: Option<PathBuf>) -> Result<()> {
 
     sched_opt.map(|(sched_policy, sched_param_i)| {
       // set sched policy
@@ -53,20 +74,54 @@ impl<S, P> Daemon<S, P>
     let sigfd = SignalFd::with_flags(&sig_mask, SFD_NONBLOCK)?;
     let fd = sigfd.as_raw_fd();
 
+    if let Some(ref notifier) = notifier {
+      prop.with_notifier(notifier.clone());
+    }
+
     let mut main = prop.setup(sig_mask).unwrap();
 
-    ::std::thread::spawn(move || {
+    let watchdog = match notifier.as_ref().and_then(|n| n.watchdog_interval()) {
+      Some(interval) => {
+        let tfd = TimerFd::new(ClockId::Monotonic)?;
+        tfd.set(TimerSpec::Periodic {
+            delay: interval,
+            interval: interval,
+          })?;
+        Some(tfd)
+      }
+      None => None,
+    };
+
+    let panics = prop.panics();
+    let main_panics = panics.clone();
+
+    // the main event loop is the prop's I/O loop 0
+    let main_thread = thread::Builder::new().name("rux-io-0".to_owned()).spawn(move || {
+      let _guard = main_panics.as_ref().map(PanicFd::guard);
       sig_mask.thread_block().unwrap();
       info!("{:?} starting main event loop", unistd::getpid());
       // run prop's I/O event loop(s)
-      main.run();
-    });
+      let res = main.run();
+
+      if let (&Err(_), Some(panics)) = (&res, main_panics) {
+        panics.signal();
+      }
+
+      res
+    })?;
 
-    let mut aux = Epoll::new_with(Default::default(), |_| {
+    let mut aux = Epoll::new_with(Default::default(), |epfd| {
       Daemon {
+        epfd: epfd,
         sigfd: sigfd,
         sig_h: sig_h,
         prop: prop,
+        notifier: notifier,
+        watchdog: watchdog,
+        handoff: None,
+        successor: None,
+        panics: panics,
+        panicked: false,
         terminating: false,
       }
     })?;
@@ -79,14 +134,215 @@ impl<S, P> Daemon<S, P>
     // register signalfd with epfd
     aux.epfd.register(fd, &siginfo)?;
 
+    if let Some(ref tfd) = aux.handler().watchdog {
+      let wdinfo = EpollEvent {
+        events: EPOLLIN,
+        data: tfd.fd as u64,
+      };
+
+      aux.epfd.register(tfd.fd, &wdinfo)?;
+    }
+
+    if let Some(ref panics) = aux.handler().panics {
+      let info = EpollEvent {
+        events: EPOLLIN,
+        data: panics.fd as u64,
+      };
+
+      aux.epfd.register(panics.fd, &info)?;
+    }
+
+    // bound only now that a process this one took over from has released it
+    if let Some(path) = handoff {
+      aux.handler_mut().serve_handoff(path);
+    }
+
+    aux.handler_mut().notify(|n| n.ready());
+
     // run aux event loop
     info!("{:?} starting aux event loop", unistd::getpid());
-    aux.run();
+    let res = aux.run();
+
+    if res.is_err() && !aux.handler().terminating {
+      error!("aux event loop failed. Shutting down ..");
+      aux.handler_mut().prop.shutdown();
+    }
+
+    // prop's shutdown has stopped its main event loop
+    main_thread.join().map_err(|_| "main event loop panicked")??;
+    info!("{:?} stopped main event loop", unistd::getpid());
+
+    res?;
+
+    if aux.handler().panicked {
+      return Err("an I/O event loop panicked or failed".into());
+    }
 
     Ok(())
   }
 }
 
+impl<S, P> Daemon<S, P> {
+  fn notify<F>(&self, f: F)
+    where F: FnOnce(&Notifier) -> Result<()>,
+  {
+    if let Some(ref notifier) = self.notifier {
+      if let Err(e) = f(notifier) {
+        error!("sd_notify: {}", e);
+      }
+    }
+  }
+
+  fn on_watchdog(&mut self) {
+    let expired = match self.watchdog {
+      Some(ref tfd) => tfd.read(),
+      None => return,
+    };
+
+    match expired {
+      Ok(Some(_)) => self.notify(|n| n.ping()),
+      Ok(None) => debug!("watchdog: timer not ready"),
+      Err(e) => error!("watchdog: {}", e),
+    }
+  }
+}
+
+impl<S, P> Daemon<S, P>
+  where P: Prop,
+{
+  fn serve_handoff(&mut self, path: PathBuf) {
+    let handoff = match Handoff::bind(&path) {
+      Ok(handoff) => handoff,
+      Err(e) => {
+        error!("handoff: could not serve {}: {}", path.display(), e);
+        return;
+      }
+    };
+
+    let info = EpollEvent {
+      events: EPOLLIN,
+      data: handoff.fd() as u64,
+    };
+
+    match self.epfd.register(handoff.fd(), &info) {
+      Ok(()) => self.handoff = Some(handoff),
+      Err(e) => error!("handoff: {}", e),
+    }
+  }
+
+  fn on_panic(&mut self) {
+    match self.panics.as_ref().map(|p| p.read()) {
+      Some(Ok(0)) | None => return,
+      Some(Ok(_)) => {}
+      Some(Err(e)) => {
+        error!("panics: {}", e);
+        return;
+      }
+    }
+
+    if self.terminating {
+      return;
+    }
+
+    error!("an I/O event loop panicked or failed. Shutting down ..");
+    self.panicked = true;
+    self.notify(|n| n.stopping());
+    self.prop.shutdown();
+    self.terminating = true;
+  }
+
+  fn on_handoff(&mut self) {
+    let successor = match self.handoff.as_ref().map(|h| h.accept()) {
+      Some(Ok(Some(successor))) => successor,
+      Some(Ok(None)) | None => return,
+      Some(Err(e)) => {
+        error!("handoff: {}", e);
+        return;
+      }
+    };
+
+    // release the path for the new process to serve its own handoff socket
+    let path = self.handoff.take().map(|h| h.path().to_owned()).unwrap();
+
+    // the reply is awaited on this loop, which keeps pinging the watchdog
+    let pending = successor.hand_off(&self.prop.listen_fds()).and_then(|()| {
+      let interval = self.notifier.as_ref().and_then(|n| n.watchdog_interval());
+      let deadline = TimerFd::new(ClockId::Monotonic)?;
+      deadline.set(TimerSpec::Oneshot(handoff::timeout(interval)))?;
+
+      for fd in &[successor.fd(), deadline.fd] {
+        let info = EpollEvent {
+          events: EPOLLIN,
+          data: *fd as u64,
+        };
+
+        self.epfd.register(*fd, &info)?;
+      }
+
+      Ok(deadline)
+    });
+
+    match pending {
+      Ok(deadline) => {
+        self.successor = Some(Pending {
+          successor: successor,
+          path: path,
+          deadline: deadline,
+        });
+      }
+      Err(e) => {
+        error!("handoff: {}", e);
+        self.serve_handoff(path);
+      }
+    }
+  }
+
+  fn on_successor(&mut self) {
+    let taken_over = match self.successor.as_ref().map(|p| p.successor.taken_over()) {
+      Some(Ok(taken_over)) => taken_over,
+      Some(Err(e)) => {
+        error!("handoff: {}", e);
+        self.abort_handoff();
+        return;
+      }
+      None => return,
+    };
+
+    if !taken_over {
+      return;
+    }
+
+    self.unregister_successor();
+
+    warn!("handoff: listening sockets taken over. Shutting down ..");
+    self.prop.handed_off();
+    self.notify(|n| n.stopping());
+    self.prop.shutdown();
+    self.terminating = true;
+  }
+
+  /// Keep serving after the successor failed to reply in time.
+  fn abort_handoff(&mut self) {
+    if let Some(pending) = self.unregister_successor() {
+      self.serve_handoff(pending.path);
+    }
+  }
+
+  fn unregister_successor(&mut self) -> Option<Pending> {
+    let pending = self.successor.take();
+
+    if let Some(ref pending) = pending {
+      for fd in &[pending.successor.fd(), pending.deadline.fd] {
+        if let Err(e) = self.epfd.unregister(*fd) {
+          error!("handoff: {}", e);
+        }
+      }
+    }
+
+    pending
+  }
+}
+
 impl<S, P> Drop for Daemon<S, P> {
   fn drop(&mut self) {
     // signalfd is closed by the SignalFd struct
@@ -114,14 +370,46 @@ impl<S, P> Handler<EpollEvent, EpollCmd> for Daemon<S, P>
   }
 
   fn on_next(&mut self, ev: EpollEvent) {
+    if self.watchdog.as_ref().map(|tfd| tfd.fd as u64) == Some(ev.data) {
+      self.on_watchdog();
+      return;
+    }
+
+    if self.handoff.as_ref().map(|h| h.fd() as u64) == Some(ev.data) {
+      self.on_handoff();
+      return;
+    }
+
+    if self.successor.as_ref().map(|p| p.successor.fd() as u64) == Some(ev.data) {
+      self.on_successor();
+      return;
+    }
+
+    if self.successor.as_ref().map(|p| p.deadline.fd as u64) == Some(ev.data) {
+      error!("handoff: successor did not reply in time");
+      self.abort_handoff();
+      return;
+    }
+
+    if self.panics.as_ref().map(|p| p.fd as u64) == Some(ev.data) {
+      self.on_panic();
+      return;
+    }
+
     if ev.data == self.sigfd.as_raw_fd() as u64 {
       match self.sigfd.read_signal() {
         Ok(Some(sig)) => {
           self.sig_h.on_next(Signal::from_c_int(sig.ssi_signo as i32).unwrap());
           match self.sig_h.next() {
-            DaemonCmd::Reload => self.prop.reload(),
+            DaemonCmd::Reload => {
+              self.notify(|n| n.reloading());
+              self.prop.reload();
+              self.notify(|n| n.ready());
+            }
             DaemonCmd::Shutdown => {
               warn!("received signal {:?}. Shutting down ..", sig.ssi_signo);
+              self.notify(|n| n.stopping());
+              self.prop.shutdown();
               self.terminating = true;
             },
             DaemonCmd::Continue => {}
@@ -0,0 +1,216 @@
+use RawFd;
+use error::*;
+use libc_sys::{clock_gettime, timespec, CLOCK_MONOTONIC};
+use nix::{unistd, Errno};
+use nix::sys::socket::*;
+use prop::listener::unix_abstract_addr;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+struct Socket {
+  fd: RawFd,
+  addr: SockAddr,
+}
+
+impl Drop for Socket {
+  fn drop(&mut self) {
+    let _ = unistd::close(self.fd);
+  }
+}
+
+/// Client of the service manager's notification socket (see `sd_notify(3)`).
+///
+/// Clones share the same socket so that props can post `status` updates
+/// while the `Daemon` reports its own state changes.
+#[derive(Clone)]
+pub struct Notifier {
+  socket: Arc<Socket>,
+  watchdog: Option<Duration>,
+}
+
+impl Notifier {
+  pub fn new(addr: SockAddr) -> Result<Notifier> {
+    let fd = socket(AddressFamily::Unix, SockType::Datagram, SOCK_CLOEXEC, 0)?;
+
+    Ok(Notifier {
+      socket: Arc::new(Socket {
+        fd: fd,
+        addr: addr,
+      }),
+      watchdog: None,
+    })
+  }
+
+  /// Notifier for the socket in `NOTIFY_SOCKET`, with the watchdog enabled
+  /// if `WATCHDOG_USEC` is set for this process. `None` if the service manager
+  /// does not expect notifications.
+  ///
+  /// The environment is left as it is, as by `prop::activation`.
+  pub fn from_env() -> Result<Option<Notifier>> {
+    let path = env::var_os("NOTIFY_SOCKET");
+    let interval = Notifier::watchdog_from_env()?;
+
+    let path = match path {
+      Some(path) => path,
+      None => return Ok(None),
+    };
+
+    let addr = {
+      use std::os::unix::ffi::OsStrExt;
+      let bytes = path.as_bytes();
+
+      match bytes.first() {
+        Some(&b'@') => unix_abstract_addr(&bytes[1..])?,
+        Some(&b'/') => UnixAddr::new(bytes)?,
+        _ => return Err(format!("NOTIFY_SOCKET: unsupported address {:?}", path).into()),
+      }
+    };
+
+    Ok(Some(Notifier::new(SockAddr::Unix(addr))?.watchdog(interval)))
+  }
+
+  /// Interval at which `WATCHDOG_USEC` asks this process to ping the watchdog,
+  /// if it does.
+  pub fn watchdog_from_env() -> Result<Option<Duration>> {
+    let pid = env::var("WATCHDOG_PID").ok();
+
+    match env::var("WATCHDOG_USEC") {
+      Ok(usec) => watchdog_interval(&usec, pid.as_deref(), unistd::getpid()),
+      Err(_) => Ok(None),
+    }
+  }
+
+  /// Ping the watchdog every `interval`.
+  pub fn watchdog(self, interval: Option<Duration>) -> Notifier {
+    Notifier { watchdog: interval, ..self }
+  }
+
+  /// Interval at which `Daemon` sends `WATCHDOG=1`.
+  pub fn watchdog_interval(&self) -> Option<Duration> {
+    self.watchdog
+  }
+
+  /// Send newline-separated `KEY=VALUE` assignments.
+  pub fn notify(&self, state: &str) -> Result<()> {
+    let sent = syscall!(sendto(self.socket.fd, state.as_bytes(), &self.socket.addr, MSG_DONTWAIT))?;
+
+    if sent.is_none() {
+      warn!("sd_notify: socket {} is full, dropped {:?}", self.socket.addr, state);
+    }
+
+    Ok(())
+  }
+
+  pub fn ready(&self) -> Result<()> {
+    self.notify("READY=1")
+  }
+
+  pub fn stopping(&self) -> Result<()> {
+    self.notify("STOPPING=1")
+  }
+
+  /// Start of a reload, to be followed by `ready` once it completes.
+  pub fn reloading(&self) -> Result<()> {
+    self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()?))
+  }
+
+  pub fn status(&self, status: &str) -> Result<()> {
+    self.notify(&format!("STATUS={}", status))
+  }
+
+  pub fn ping(&self) -> Result<()> {
+    self.notify("WATCHDOG=1")
+  }
+}
+
+/// Half the watchdog timeout, as recommended by `sd_watchdog_enabled(3)`,
+/// or `None` if the watchdog is meant for another process.
+fn watchdog_interval(usec: &str, pid: Option<&str>, own_pid: i32) -> Result<Option<Duration>> {
+  if let Some(pid) = pid {
+    let pid: i32 = pid.parse().map_err(|_| format!("WATCHDOG_PID: invalid pid {:?}", pid))?;
+    if pid != own_pid {
+      return Ok(None);
+    }
+  }
+
+  let usec: u64 = usec.parse().map_err(|_| format!("WATCHDOG_USEC: invalid timeout {:?}", usec))?;
+
+  if usec == 0 {
+    return Ok(None);
+  }
+
+  Ok(Some(Duration::from_micros(usec / 2)))
+}
+
+fn monotonic_usec() -> Result<u64> {
+  let mut ts = timespec {
+    tv_sec: 0,
+    tv_nsec: 0,
+  };
+
+  unsafe {
+    Errno::result(clock_gettime(CLOCK_MONOTONIC, &mut ts))?;
+  }
+
+  Ok(ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::net::UnixDatagram;
+  use std::path::PathBuf;
+  use std::time::Duration;
+  use super::*;
+
+  fn systemd() -> (UnixDatagram, Notifier, PathBuf) {
+    let path = env::temp_dir().join(format!("rux-notify-{}.sock", unistd::getpid()));
+    let _ = ::std::fs::remove_file(&path);
+
+    let systemd = UnixDatagram::bind(&path).unwrap();
+    systemd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let notifier = Notifier::new(SockAddr::Unix(UnixAddr::new(&path).unwrap())).unwrap();
+
+    (systemd, notifier, path)
+  }
+
+  fn recv(systemd: &UnixDatagram) -> String {
+    let mut buf = [0; 256];
+    let n = systemd.recv(&mut buf).unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+  }
+
+  #[test]
+  fn sends_state_changes() {
+    let (systemd, notifier, path) = systemd();
+
+    notifier.ready().unwrap();
+    assert_eq!(recv(&systemd), "READY=1");
+
+    notifier.clone().status("serving").unwrap();
+    assert_eq!(recv(&systemd), "STATUS=serving");
+
+    notifier.reloading().unwrap();
+    assert!(recv(&systemd).starts_with("RELOADING=1\nMONOTONIC_USEC="));
+
+    notifier.ping().unwrap();
+    assert_eq!(recv(&systemd), "WATCHDOG=1");
+
+    notifier.stopping().unwrap();
+    assert_eq!(recv(&systemd), "STOPPING=1");
+
+    ::std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn derives_watchdog_interval() {
+    assert_eq!(watchdog_interval("3000000", None, 42).unwrap(),
+               Some(Duration::from_millis(1500)));
+    assert_eq!(watchdog_interval("3000000", Some("42"), 42).unwrap(),
+               Some(Duration::from_millis(1500)));
+    assert_eq!(watchdog_interval("3000000", Some("43"), 42).unwrap(), None);
+    assert_eq!(watchdog_interval("0", None, 42).unwrap(), None);
+    assert!(watchdog_interval("x", None, 42).is_err());
+  }
+}
@@ -0,0 +1,231 @@
+use RawFd;
+use error::*;
+use libc_sys::{c_int, c_uint, c_void, iovec, msghdr, sa_family_t, sockaddr_storage, sockaddr_un,
+               timespec, AF_UNIX};
+use nix::Errno;
+use nix::sys::socket::{sockaddr_storage_to_addr, SockAddr, UnixAddr, MSG_DONTWAIT, MSG_TRUNC};
+use std::{mem, ptr};
+
+#[repr(C)]
+struct mmsghdr {
+  msg_hdr: msghdr,
+  msg_len: c_uint,
+}
+
+extern "C" {
+  fn recvmmsg(sockfd: c_int, msgvec: *mut mmsghdr, vlen: c_uint, flags: c_int,
+              timeout: *mut timespec)
+              -> c_int;
+
+  fn sendmmsg(sockfd: c_int, msgvec: *mut mmsghdr, vlen: c_uint, flags: c_int) -> c_int;
+}
+
+#[inline]
+fn empty_hdr() -> mmsghdr {
+  unsafe { mem::zeroed() }
+}
+
+/// Buffers for receiving up to `batch` datagrams with a single `recvmmsg`.
+pub struct RecvBatch {
+  buf: Vec<u8>,
+  max_size: usize,
+  iovs: Vec<iovec>,
+  addrs: Vec<sockaddr_storage>,
+  hdrs: Vec<mmsghdr>,
+  len: usize,
+}
+
+impl RecvBatch {
+  pub fn new(batch: usize, max_size: usize) -> RecvBatch {
+    RecvBatch {
+      buf: vec!(0; batch * max_size),
+      max_size: max_size,
+      iovs: Vec::with_capacity(batch),
+      addrs: (0..batch).map(|_| unsafe { mem::zeroed() }).collect(),
+      hdrs: (0..batch).map(|_| empty_hdr()).collect(),
+      len: 0,
+    }
+  }
+
+  /// Receive as many datagrams as are queued, up to the batch size.
+  /// Returns 0 if none was queued.
+  pub fn recv(&mut self, fd: RawFd) -> Result<usize> {
+    let batch = self.hdrs.len();
+
+    // buffers may have moved since the last call
+    self.iovs.clear();
+    for chunk in self.buf.chunks_mut(self.max_size) {
+      self.iovs.push(iovec {
+        iov_base: chunk.as_mut_ptr() as *mut c_void,
+        iov_len: chunk.len(),
+      });
+    }
+
+    for i in 0..batch {
+      let hdr = &mut self.hdrs[i].msg_hdr;
+      hdr.msg_name = &mut self.addrs[i] as *mut sockaddr_storage as *mut c_void;
+      hdr.msg_namelen = mem::size_of::<sockaddr_storage>() as u32;
+      hdr.msg_iov = &mut self.iovs[i];
+      hdr.msg_iovlen = 1;
+      hdr.msg_flags = 0;
+    }
+
+    let res = syscall!(Errno::result(unsafe {
+      recvmmsg(fd,
+               self.hdrs.as_mut_ptr(),
+               batch as c_uint,
+               MSG_DONTWAIT.bits(),
+               ptr::null_mut())
+    }))?;
+
+    self.len = res.unwrap_or(0) as usize;
+
+    Ok(self.len)
+  }
+
+  /// Payload and sender of the `i`th datagram of the last `recv`.
+  pub fn get(&self, i: usize) -> Result<(&[u8], SockAddr)> {
+    assert!(i < self.len, "datagram {} out of bounds", i);
+
+    let hdr = &self.hdrs[i];
+    let start = i * self.max_size;
+    let data = &self.buf[start..start + hdr.msg_len as usize];
+
+    if hdr.msg_hdr.msg_flags & MSG_TRUNC.bits() != 0 {
+      debug!("recvmmsg: datagram truncated to {} bytes", self.max_size);
+    }
+
+    let len = hdr.msg_hdr.msg_namelen as usize;
+    let addr = &self.addrs[i];
+
+    let peer = if len <= mem::size_of::<sa_family_t>() {
+      // unbound AF_UNIX peers come with no address, or with the family only
+      let mut unnamed: sockaddr_un = unsafe { mem::zeroed() };
+      unnamed.sun_family = AF_UNIX as sa_family_t;
+      SockAddr::Unix(UnixAddr(unnamed, 0))
+    } else if addr.ss_family as c_int == AF_UNIX {
+      // nix's UnixAddr holds the length of the path only
+      let un = unsafe { *(addr as *const sockaddr_storage as *const sockaddr_un) };
+      SockAddr::Unix(UnixAddr(un, len - mem::size_of::<sa_family_t>()))
+    } else {
+      unsafe { sockaddr_storage_to_addr(addr, len)? }
+    };
+
+    Ok((data, peer))
+  }
+}
+
+unsafe impl Send for RecvBatch {}
+
+/// Queue of outgoing datagrams, flushed with `sendmmsg`.
+pub struct Replies {
+  buf: Vec<u8>,
+  msgs: Vec<(SockAddr, usize, usize)>,
+  capacity: usize,
+  iovs: Vec<iovec>,
+  hdrs: Vec<mmsghdr>,
+}
+
+impl Replies {
+  pub fn with_capacity(capacity: usize) -> Replies {
+    Replies {
+      buf: Vec::with_capacity(capacity),
+      msgs: Vec::new(),
+      capacity: capacity,
+      iovs: Vec::new(),
+      hdrs: Vec::new(),
+    }
+  }
+
+  /// Queue `data` to be sent to `peer` once the current batch is handled.
+  pub fn send(&mut self, peer: &SockAddr, data: &[u8]) -> Result<()> {
+    if self.buf.len() + data.len() > self.capacity {
+      return Err(ErrorKind::OutOfCapacity(self.capacity).into());
+    }
+
+    let start = self.buf.len();
+    self.buf.extend_from_slice(data);
+    self.msgs.push((*peer, start, self.buf.len()));
+
+    Ok(())
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.msgs.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.msgs.is_empty()
+  }
+
+  /// Send queued datagrams until the queue is empty or the socket would block.
+  ///
+  /// A datagram that the kernel refuses is dropped and the error returned,
+  /// the rest stay queued.
+  pub fn flush(&mut self, fd: RawFd) -> Result<()> {
+    while !self.msgs.is_empty() {
+      self.iovs.clear();
+      self.hdrs.clear();
+
+      for &(_, start, end) in &self.msgs {
+        self.iovs.push(iovec {
+          iov_base: self.buf[start..end].as_ptr() as *mut c_void,
+          iov_len: end - start,
+        });
+      }
+
+      for (i, (peer, _, _)) in self.msgs.iter().enumerate() {
+        let mut hdr = empty_hdr();
+        let (addr, len) = unsafe { peer.as_ffi_pair() };
+        hdr.msg_hdr.msg_name = addr as *const _ as *mut c_void;
+        hdr.msg_hdr.msg_namelen = len;
+        hdr.msg_hdr.msg_iov = &mut self.iovs[i];
+        hdr.msg_hdr.msg_iovlen = 1;
+        self.hdrs.push(hdr);
+      }
+
+      let res = syscall!(Errno::result(unsafe {
+        sendmmsg(fd,
+                 self.hdrs.as_mut_ptr(),
+                 self.hdrs.len() as c_uint,
+                 MSG_DONTWAIT.bits())
+      }));
+
+      match res {
+        Ok(Some(sent)) => self.consume(sent as usize),
+        Ok(None) => {
+          debug!("sendmmsg: socket not ready, {} datagrams queued", self.msgs.len());
+          return Ok(());
+        }
+        Err(e) => {
+          self.consume(1);
+          return Err(e);
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  fn consume(&mut self, n: usize) {
+    if n >= self.msgs.len() {
+      self.msgs.clear();
+      self.buf.clear();
+      return;
+    }
+
+    let offset = self.msgs[n].1;
+
+    self.msgs.drain(..n);
+    self.buf.drain(..offset);
+
+    for msg in self.msgs.iter_mut() {
+      msg.1 -= offset;
+      msg.2 -= offset;
+    }
+  }
+}
+
+unsafe impl Send for Replies {}
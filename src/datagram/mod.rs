@@ -0,0 +1,376 @@
+use RawFd;
+use epoll::*;
+use error::*;
+use handler::Handler;
+use mux::Action;
+use nix::sys::socket::SockAddr;
+use std::collections::HashMap;
+
+mod mmsg;
+
+pub use self::mmsg::{RecvBatch, Replies};
+
+#[derive(Debug, Copy, Clone)]
+pub struct DatagramConfig {
+  /// Datagrams read with a single `recvmmsg`
+  pub batch: usize,
+  /// Size of the receive buffer of each datagram; longer datagrams are truncated
+  pub max_size: usize,
+  /// Bytes of replies that can be queued per socket
+  pub reply_capacity: usize,
+}
+
+impl Default for DatagramConfig {
+  fn default() -> DatagramConfig {
+    DatagramConfig {
+      batch: 32,
+      max_size: 2048,
+      reply_capacity: 32 * 2048,
+    }
+  }
+}
+
+pub struct Datagram<'d> {
+  pub data: &'d [u8],
+  pub peer: SockAddr,
+  pub replies: &'d mut Replies,
+}
+
+/// Epoll handler for datagram sockets registered with `Action::New`.
+///
+/// Datagrams are read in batches and handed to a single handler per event loop.
+/// Replies go to a queue of the socket the datagram came in on, flushed with
+/// `sendmmsg` right after the batch; the socket is polled for writability
+/// while replies that would block stay queued.
+pub struct SyncDatagram<H> {
+  epfd: EpollFd,
+  handler: H,
+  config: DatagramConfig,
+  batch: RecvBatch,
+  replies: HashMap<RawFd, Outbox>,
+  draining: bool,
+}
+
+/// Replies to send on a socket.
+struct Outbox {
+  replies: Replies,
+  /// Whether `EPOLLOUT` is part of the interest in the socket
+  writable: bool,
+}
+
+impl<H> SyncDatagram<H>
+  where H: for<'d> Handler<Datagram<'d>, ()>,
+{
+  pub fn new(config: DatagramConfig, epfd: EpollFd, handler: H) -> SyncDatagram<H> {
+    SyncDatagram {
+      epfd: epfd,
+      handler: handler,
+      config: config,
+      batch: RecvBatch::new(config.batch, config.max_size),
+      replies: HashMap::new(),
+      draining: false,
+    }
+  }
+
+  pub fn handler(&self) -> &H {
+    &self.handler
+  }
+
+  fn on_readable(&mut self, sockfd: RawFd) -> Result<()> {
+    let n = self.batch.recv(sockfd)?;
+
+    let capacity = self.config.reply_capacity;
+    let outbox = self.replies.entry(sockfd).or_insert_with(|| {
+      Outbox {
+        replies: Replies::with_capacity(capacity),
+        writable: false,
+      }
+    });
+
+    for i in 0..n {
+      let (data, peer) = match self.batch.get(i) {
+        Ok(dgram) => dgram,
+        Err(e) => {
+          report_err!(e);
+          continue;
+        }
+      };
+
+      self.handler.on_next(Datagram {
+        data: data,
+        peer: peer,
+        replies: &mut outbox.replies,
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Send the replies queued for `sockfd`, polling it for writability
+  /// for as long as some are left.
+  fn flush(&mut self, sockfd: RawFd, listener: usize) -> Result<()> {
+    let outbox = match self.replies.get_mut(&sockfd) {
+      Some(outbox) => outbox,
+      None => return Ok(()),
+    };
+
+    let res = outbox.replies.flush(sockfd);
+
+    let writable = !outbox.replies.is_empty();
+    if writable != outbox.writable {
+      let interest = EpollEvent {
+        events: if writable {
+          Self::interests() | EPOLLOUT
+        } else {
+          Self::interests()
+        },
+        data: Action::encode(Action::New(sockfd, listener)),
+      };
+
+      // EPOLLEXCLUSIVE interests cannot be modified
+      self.epfd.unregister(sockfd)?;
+      self.epfd.register(sockfd, &interest)?;
+      outbox.writable = writable;
+    }
+
+    res
+  }
+}
+
+impl<H> Handler<EpollEvent, EpollCmd> for SyncDatagram<H>
+  where H: for<'d> Handler<Datagram<'d>, ()>,
+{
+  fn next(&mut self) -> EpollCmd {
+    if self.draining {
+      return EpollCmd::Shutdown;
+    }
+
+    EpollCmd::Poll
+  }
+
+  fn on_next(&mut self, event: EpollEvent) {
+    let events = event.events;
+
+    if let Action::New(sockfd, listener) = Action::decode(event.data) {
+      if events != EPOLLOUT {
+        if let Err(e) = self.on_readable(sockfd) {
+          report_err!(e);
+        }
+      }
+
+      if let Err(e) = self.flush(sockfd, listener) {
+        report_err!(e);
+      }
+    }
+  }
+
+  fn on_error(&mut self, error: Error) -> Result<()> {
+    Handler::<Datagram<'static>, ()>::on_error(&mut self.handler, error)
+  }
+}
+
+impl<H> EpollHandler for SyncDatagram<H> {
+  fn interests() -> EpollEventKind {
+    EPOLLIN | EPOLLEXCLUSIVE
+  }
+
+  fn with_epfd(&mut self, epfd: EpollFd) {
+    self.epfd = epfd;
+  }
+
+  /// Datagrams carry no state across events, so stop right away.
+  fn drain(&mut self) {
+    self.draining = true;
+  }
+
+  fn retire(&mut self, sockfd: RawFd) {
+    self.replies.remove(&sockfd);
+  }
+}
+
+impl<H: Clone> Clone for SyncDatagram<H> {
+  fn clone(&self) -> Self {
+    SyncDatagram {
+      epfd: self.epfd.clone(),
+      handler: self.handler.clone(),
+      config: self.config,
+      batch: RecvBatch::new(self.config.batch, self.config.max_size),
+      replies: HashMap::new(),
+      draining: self.draining,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use epoll::*;
+  use handler::Handler;
+  use mux::Action;
+  use nix::unistd;
+  use std::env;
+  use std::fs;
+  use std::net::UdpSocket;
+  use std::os::unix::io::AsRawFd;
+  use std::os::unix::net::UnixDatagram;
+  use std::path::PathBuf;
+  use std::time::{Duration, Instant};
+  use super::*;
+
+  #[derive(Clone)]
+  struct Echo {
+    received: usize,
+  }
+
+  impl<'d> Handler<Datagram<'d>, ()> for Echo {
+    fn next(&mut self) {}
+
+    fn on_next(&mut self, dgram: Datagram<'d>) {
+      self.received += 1;
+      dgram.replies.send(&dgram.peer, dgram.data).unwrap();
+    }
+  }
+
+  #[test]
+  fn echoes_batch_of_datagrams() {
+    let config = DatagramConfig {
+      batch: 4,
+      ..Default::default()
+    };
+
+    let mut poll = Epoll::new_with(Default::default(),
+                                   |epfd| SyncDatagram::new(config, epfd, Echo { received: 0 }))
+      .unwrap();
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_nonblocking(true).unwrap();
+
+    let interest = EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::New(server.as_raw_fd(), 0)),
+    };
+    poll.epfd.register(server.as_raw_fd(), &interest).unwrap();
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    for i in 0..6_u8 {
+      client.send_to(&[i; 3], server.local_addr().unwrap()).unwrap();
+    }
+
+    while poll.handler().handler().received < 6 {
+      poll.run_once().unwrap();
+    }
+
+    for i in 0..6_u8 {
+      let mut buf = [0; 16];
+      let (n, from) = client.recv_from(&mut buf).unwrap();
+      assert_eq!(from, server.local_addr().unwrap());
+      assert_eq!(&buf[..n], &[i; 3]);
+    }
+  }
+
+  fn register(poll: &Epoll<SyncDatagram<Echo>>, sockfd: ::RawFd) {
+    let interest = EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::New(sockfd, 0)),
+    };
+    poll.epfd.register(sockfd, &interest).unwrap();
+  }
+
+  fn socket_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("rux-dgram-{}-{}.sock", name, unistd::getpid()));
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  #[test]
+  fn retries_replies_once_writable() {
+    let config = EpollConfig {
+      loop_ms: 10,
+      ..Default::default()
+    };
+
+    let mut poll = Epoll::new_with(config, |epfd| {
+        SyncDatagram::new(Default::default(), epfd, Echo { received: 0 })
+      })
+      .unwrap();
+
+    let (server_path, client_path) = (socket_path("server"), socket_path("client"));
+
+    let server = UnixDatagram::bind(&server_path).unwrap();
+    server.set_nonblocking(true).unwrap();
+    register(&poll, server.as_raw_fd());
+
+    let client = UnixDatagram::bind(&client_path).unwrap();
+    client.set_nonblocking(true).unwrap();
+
+    // twice as many replies as the client's receive queue takes
+    // (net.unix.max_dgram_qlen), so some are left queued
+    let mut sent = 0_u8;
+    let mut replies = Vec::new();
+    let start = Instant::now();
+
+    while replies.len() < 20 {
+      assert!(start.elapsed() < Duration::from_secs(5), "replies were not retried");
+
+      if sent < 20 && client.send_to(&[sent], &server_path).is_ok() {
+        sent += 1;
+      }
+
+      poll.run_once().unwrap();
+
+      // read only once all datagrams are handled, to fill the client's queue
+      if poll.handler().handler().received == 20 {
+        let mut buf = [0; 1];
+        while let Ok(n) = client.recv(&mut buf) {
+          replies.extend_from_slice(&buf[..n]);
+        }
+      }
+    }
+
+    assert_eq!(replies, (0..20).collect::<Vec<u8>>());
+
+    fs::remove_file(&server_path).unwrap();
+    fs::remove_file(&client_path).unwrap();
+  }
+
+  #[test]
+  fn receives_from_unbound_peers() {
+    let config = EpollConfig {
+      loop_ms: 10,
+      ..Default::default()
+    };
+
+    let mut poll = Epoll::new_with(config, |epfd| {
+        SyncDatagram::new(Default::default(), epfd, Echo { received: 0 })
+      })
+      .unwrap();
+
+    let path = socket_path("unbound");
+    let server = UnixDatagram::bind(&path).unwrap();
+    server.set_nonblocking(true).unwrap();
+    register(&poll, server.as_raw_fd());
+
+    UnixDatagram::unbound().unwrap().send_to(b"anyone?", &path).unwrap();
+
+    let start = Instant::now();
+    while poll.handler().handler().received < 1 {
+      assert!(start.elapsed() < Duration::from_secs(5), "datagram was dropped");
+      poll.run_once().unwrap();
+    }
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn replies_are_bounded() {
+    let mut replies = Replies::with_capacity(4);
+    let peer = SockAddr::new_inet(::nix::sys::socket::InetAddr::from_std(&"127.0.0.1:1"
+      .parse()
+      .unwrap()));
+
+    replies.send(&peer, b"abc").unwrap();
+    assert!(replies.send(&peer, b"de").is_err());
+    assert_eq!(replies.len(), 1);
+  }
+}
@@ -0,0 +1,115 @@
+use RawFd;
+use epoll::EpollConfig;
+use error::*;
+use handler::Handler;
+use libc_sys::{eventfd, EFD_CLOEXEC, EFD_NONBLOCK};
+use nix::{unistd, Errno};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub type Task<H> = Box<dyn FnOnce(&mut H) + Send>;
+
+pub enum Request<H> {
+  Run(Task<H>),
+  Shutdown(Duration),
+  Configure(EpollConfig),
+}
+
+pub struct Shared<H> {
+  pub efd: RawFd,
+  tasks: Mutex<VecDeque<Request<H>>>,
+}
+
+impl<H> Shared<H> {
+  pub fn new() -> Result<Shared<H>> {
+    let efd = unsafe { Errno::result(eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK))? };
+
+    Ok(Shared {
+      efd: efd,
+      tasks: Mutex::new(VecDeque::new()),
+    })
+  }
+
+  /// Reset the eventfd counter.
+  pub fn clear(&self) -> Result<()> {
+    let mut buf = [0_u8; 8];
+    syscall!(unistd::read(self.efd, &mut buf))?;
+    Ok(())
+  }
+
+  /// Pop the next task without holding the lock while it runs,
+  /// so that tasks can enqueue other tasks.
+  #[inline]
+  pub fn pop(&self) -> Option<Request<H>> {
+    self.tasks.lock().unwrap().pop_front()
+  }
+
+  fn push(&self, req: Request<H>) {
+    self.tasks.lock().unwrap().push_back(req);
+  }
+}
+
+impl<H> Drop for Shared<H> {
+  fn drop(&mut self) {
+    let _ = unistd::close(self.efd);
+  }
+}
+
+/// Send + Clone handle to an `Epoll` event loop.
+///
+/// Closures and messages submitted through the handle are queued and run
+/// on the loop's thread, with exclusive access to its handler, right after
+/// the loop wakes up from `epoll_wait`.
+pub struct EpollHandle<H> {
+  shared: Arc<Shared<H>>,
+}
+
+impl<H> EpollHandle<H> {
+  pub fn new(shared: Arc<Shared<H>>) -> EpollHandle<H> {
+    EpollHandle { shared: shared }
+  }
+
+  pub fn spawn<F>(&self, task: F) -> Result<()>
+    where F: FnOnce(&mut H) + Send + 'static,
+  {
+    self.shared.push(Request::Run(Box::new(task)));
+    self.wakeup()
+  }
+
+  /// Ask the loop to stop once its handler's `next` returns `EpollCmd::Shutdown`,
+  /// or unconditionally after `grace` has elapsed.
+  ///
+  /// Tasks submitted before this call still run.
+  pub fn shutdown(&self, grace: Duration) -> Result<()> {
+    self.shared.push(Request::Shutdown(grace));
+    self.wakeup()
+  }
+
+  /// Apply `config` from the next iteration of the loop on.
+  pub fn configure(&self, config: EpollConfig) -> Result<()> {
+    self.shared.push(Request::Configure(config));
+    self.wakeup()
+  }
+
+  /// Deliver `msg` to the handler's `on_next`.
+  pub fn send<M, O>(&self, msg: M) -> Result<()>
+    where H: Handler<M, O>,
+          M: Send + 'static,
+  {
+    self.spawn(move |h| h.on_next(msg))
+  }
+
+  /// Wake up the loop without submitting any task.
+  pub fn wakeup(&self) -> Result<()> {
+    // EAGAIN means the counter is saturated and the loop is already due to wake up
+    syscall!(unistd::write(self.shared.efd, &1_u64.to_ne_bytes()))?;
+    Ok(())
+  }
+}
+
+impl<H> Clone for EpollHandle<H> {
+  fn clone(&self) -> Self {
+    EpollHandle { shared: self.shared.clone() }
+  }
+}
@@ -1,5 +1,5 @@
 use RawFd;
-use error::Result;
+use error::*;
 use handler::Handler;
 
 pub use nix::sys::epoll::{epoll_create, EpollEvent, EpollEventKind, EPOLLIN, EPOLLOUT, EPOLLERR,
@@ -7,7 +7,22 @@ pub use nix::sys::epoll::{epoll_create, EpollEvent, EpollEventKind, EPOLLIN, EPO
 
 use nix::sys::epoll::{epoll_ctl, epoll_wait, EpollOp};
 use nix::unistd;
+use std::cmp;
 use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+mod handle;
+pub(crate) mod stats;
+mod uring;
+
+pub use self::handle::{EpollHandle, Task};
+pub use self::stats::{EpollCounters, EpollStats};
+pub use self::uring::{Completion, Op};
+use self::handle::{Request, Shared};
+
+/// Epoll data reserved for the wakeup eventfd of `EpollHandle`
+const WAKEUP: u64 = u64::MAX;
 
 lazy_static! {
     static ref NO_INTEREST: EpollEvent = {
@@ -18,10 +33,28 @@ lazy_static! {
     };
 }
 
+/// Settings of an `Epoll`, built from `EpollConfig::default()` with the
+/// methods below so that new settings can be added.
 #[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
 pub struct EpollConfig {
   pub loop_ms: isize,
   pub buffer_capacity: usize,
+  /// Keep the counters returned by `Epoll::counters` up to date
+  pub stats: bool,
+  /// What the loop waits for events with; only taken into account when the
+  /// loop is created
+  pub backend: Backend,
+}
+
+/// Event notification mechanism of an `Epoll`. Handlers see the same
+/// `EpollEvent`s and register their interests with the same `EpollFd`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+  Epoll,
+  /// io_uring poll requests, from Linux 5.13, which also runs the `Op`s
+  /// submitted with `EpollFd::submit`
+  Uring,
 }
 
 pub struct Epoll<H> {
@@ -29,11 +62,20 @@ pub struct Epoll<H> {
   handler: H,
   loop_ms: isize,
   buf: Vec<EpollEvent>,
+  shared: Option<Arc<Shared<H>>>,
+  deadline: Option<Instant>,
+  start: Option<Task<H>>,
+  stats: bool,
+  counters: Arc<EpollCounters>,
+  /// Poll requests of the ring to arm again once their events are handled
+  rearm: Vec<u64>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct EpollFd {
   pub fd: RawFd,
+  /// Ring of the `Backend::Uring` instance `fd`
+  ring: Option<Arc<uring::Ring>>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -47,6 +89,13 @@ pub trait EpollHandler {
   fn interests() -> EpollEventKind;
 
   fn with_epfd(&mut self, epfd: EpollFd);
+
+  /// Stop taking on new work; `next` should return `EpollCmd::Shutdown`
+  /// once the work in flight is done.
+  fn drain(&mut self) {}
+
+  /// Forget the listening socket `fd`, about to be closed.
+  fn retire(&mut self, _: RawFd) {}
 }
 
 unsafe impl<H> Send for Epoll<H> {}
@@ -58,6 +107,12 @@ impl<'h, H: Handler<EpollEvent, EpollCmd> + 'h> Epoll<H> {
       loop_ms: config.loop_ms,
       handler: handler,
       buf: Vec::with_capacity(config.buffer_capacity),
+      shared: None,
+      deadline: None,
+      start: None,
+      stats: config.stats,
+      counters: Arc::new(EpollCounters::default()),
+      rearm: Vec::new(),
     }
   }
 
@@ -65,34 +120,173 @@ impl<'h, H: Handler<EpollEvent, EpollCmd> + 'h> Epoll<H> {
     where F: FnOnce(EpollFd) -> H,
   {
 
-    let fd = epoll_create()?;
+    let epfd = EpollFd::create(config.backend)?;
 
-    let epfd = EpollFd { fd: fd };
-
-    let handler = newctl(epfd);
+    let handler = newctl(epfd.clone());
 
     Ok(Self::from_fd(epfd, handler, config))
   }
 
+  /// Handle to submit tasks to this event loop from other threads.
+  pub fn handle(&mut self) -> Result<EpollHandle<H>> {
+    if self.shared.is_none() {
+      let shared = Shared::new()?;
+
+      let interest = EpollEvent {
+        events: EPOLLIN,
+        data: WAKEUP,
+      };
+
+      self.epfd.register(shared.efd, &interest)?;
+      self.shared = Some(Arc::new(shared));
+    }
+
+    Ok(EpollHandle::new(self.shared.as_ref().unwrap().clone()))
+  }
+
+  /// Wait for events and dispatch them to the handler, retrying when interrupted
+  /// by a signal. Other errors of `epoll_wait` go to `Handler::on_error`.
   #[inline]
-  pub fn run_once(&mut self) -> EpollCmd {
+  pub fn run_once(&mut self) -> Result<EpollCmd> {
+    let mut wakeup = false;
+    let timeout = self.timeout();
+    let start = if self.stats { Some(Instant::now()) } else { None };
+
+    if let Err(e) = self.wait(timeout) {
+      self.handler.on_error(e)?;
+    }
+
+    let waited = start.map(|_| Instant::now());
+    let cnt = self.buf.len();
+
+    for ev in self.buf.drain(..) {
+      if ev.data == WAKEUP {
+        wakeup = true;
+        continue;
+      }
+      self.handler.on_next(ev);
+    }
+
+    if let (Some(start), Some(waited)) = (start, waited) {
+      self.counters.record(cnt, self.buf.capacity(), waited - start, waited.elapsed());
+    }
+
+    if let Some(ref ring) = self.epfd.ring {
+      if let Err(e) = ring.rearm(&mut self.rearm) {
+        self.handler.on_error(e)?;
+      }
+    }
+
+    if wakeup {
+      self.run_tasks();
+    }
+
+    let cmd = match self.handler.next() {
+      EpollCmd::Poll => {
+        match self.deadline {
+          Some(deadline) if Instant::now() >= deadline => {
+            warn!("epoll {}: shutdown grace period elapsed", self.epfd);
+            EpollCmd::Shutdown
+          }
+          _ => EpollCmd::Poll,
+        }
+      }
+      cmd => cmd,
+    };
+
+    Ok(cmd)
+  }
+
+  /// Fill the buffer with the events that are ready, waiting up to `timeout` ms.
+  #[inline]
+  fn wait(&mut self, timeout: isize) -> Result<()> {
+    if let Some(ref ring) = self.epfd.ring {
+      return ring.wait(&mut self.buf, timeout, &mut self.rearm);
+    }
+
     unsafe {
       let dst = ::std::slice::from_raw_parts_mut(self.buf.as_mut_ptr(), self.buf.capacity());
-      let cnt = epoll_wait(self.epfd.fd, dst, self.loop_ms).unwrap();
-      self.buf.set_len(cnt);
+      let cnt = syscall!(epoll_wait(self.epfd.fd, dst, timeout))?;
+      self.buf.set_len(cnt.unwrap_or(0));
+    }
+
+    Ok(())
+  }
 
-      for ev in self.buf.drain(..) {
-        self.handler.on_next(ev);
+  #[inline]
+  fn timeout(&self) -> isize {
+    match self.deadline {
+      Some(deadline) => {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let ms = (left.as_secs() * 1000) as isize + left.subsec_millis() as isize + 1;
+
+        if self.loop_ms < 0 {
+          ms
+        } else {
+          cmp::min(ms, self.loop_ms)
+        }
       }
+      None => self.loop_ms,
+    }
+  }
 
-      self.handler.next()
+  fn run_tasks(&mut self) {
+    let shared = match self.shared {
+      Some(ref shared) => shared.clone(),
+      None => return,
+    };
+
+    if let Err(e) = shared.clear() {
+      report_err!(e);
     }
+
+    while let Some(req) = shared.pop() {
+      match req {
+        Request::Run(task) => task(&mut self.handler),
+        Request::Shutdown(grace) => {
+          let deadline = Instant::now() + grace;
+          self.deadline = Some(self.deadline.map_or(deadline, |d| cmp::min(d, deadline)));
+        }
+        Request::Configure(config) => {
+          self.loop_ms = config.loop_ms;
+          self.stats = config.stats;
+          if config.buffer_capacity != self.buf.capacity() {
+            self.buf = Vec::with_capacity(config.buffer_capacity);
+          }
+        }
+      }
+    }
+  }
+
+  /// Counters of the loop, updated while `EpollConfig::stats` is set.
+  pub fn counters(&self) -> Arc<EpollCounters> {
+    self.counters.clone()
   }
 
-  pub fn run(&mut self) {
+  pub fn handler(&self) -> &H {
+    &self.handler
+  }
+
+  pub fn handler_mut(&mut self) -> &mut H {
+    &mut self.handler
+  }
+
+  /// Run `f` on the thread of the loop when it starts, before waiting for events.
+  pub fn before_run<F>(&mut self, f: F)
+    where F: FnOnce(&mut H) + Send + 'static,
+  {
+    self.start = Some(Box::new(f));
+  }
+
+  /// Run until the handler asks for a shutdown, or fails.
+  pub fn run(&mut self) -> Result<()> {
+    if let Some(start) = self.start.take() {
+      start(&mut self.handler);
+    }
+
     loop {
-      if let EpollCmd::Shutdown = self.run_once() {
-        return;
+      if let EpollCmd::Shutdown = self.run_once()? {
+        return Ok(());
       }
     }
   }
@@ -100,7 +294,10 @@ impl<'h, H: Handler<EpollEvent, EpollCmd> + 'h> Epoll<H> {
 
 impl<H> Drop for Epoll<H> {
   fn drop(&mut self) {
-    let _ = unistd::close(self.epfd.fd);
+    // a ring is closed once the last `EpollFd` of it is dropped
+    if self.epfd.ring.is_none() {
+      let _ = unistd::close(self.epfd.fd);
+    }
   }
 }
 
@@ -108,11 +305,50 @@ unsafe impl Send for EpollFd {}
 
 impl EpollFd {
   pub fn new(fd: RawFd) -> EpollFd {
-    EpollFd { fd: fd }
+    EpollFd {
+      fd: fd,
+      ring: None,
+    }
+  }
+
+  /// New epoll instance, or io_uring instance registering interests as
+  /// poll requests, for an `Epoll` to run.
+  pub fn create(backend: Backend) -> Result<EpollFd> {
+    match backend {
+      Backend::Epoll => Ok(EpollFd::new(epoll_create()?)),
+      Backend::Uring => {
+        let ring = Arc::new(uring::Ring::new()?);
+
+        Ok(EpollFd {
+          fd: ring.fd(),
+          ring: Some(ring),
+        })
+      }
+    }
+  }
+
+  /// Run `op` on the ring of a `Backend::Uring` loop, from the loop's thread.
+  /// Its completion is an event carrying `data`: `EPOLLIN` for `Op::Accept`
+  /// and `Op::Recv`, `EPOLLOUT` for `Op::Send` and `EPOLLERR` if it failed.
+  /// `data` stands for the operation until its result is taken with `completion`.
+  pub fn submit(&self, op: Op, data: u64) -> Result<()> {
+    match self.ring {
+      Some(ref ring) => ring.submit(op, data),
+      None => Err("submit: operations need the io_uring backend".into()),
+    }
+  }
+
+  /// Result of the operation submitted with `data`, once its event is delivered.
+  pub fn completion(&self, data: u64) -> Option<Completion> {
+    self.ring.as_ref().and_then(|ring| ring.completion(data))
   }
 
   #[inline]
   fn ctl(&self, op: EpollOp, interest: &EpollEvent, fd: RawFd) -> Result<()> {
+    if let Some(ref ring) = self.ring {
+      return ring.ctl(op, fd, interest);
+    }
+
     epoll_ctl(self.fd, op, fd, interest)?;
     Ok(())
   }
@@ -153,10 +389,33 @@ impl Default for EpollConfig {
     EpollConfig {
       loop_ms: -1,
       buffer_capacity: 256,
+      stats: false,
+      backend: Backend::Epoll,
     }
   }
 }
 
+impl EpollConfig {
+  /// How long each wait for events lasts in milliseconds, -1 for as long as
+  /// there are none.
+  pub fn loop_ms(self, loop_ms: isize) -> EpollConfig {
+    EpollConfig { loop_ms: loop_ms, ..self }
+  }
+
+  /// Most events handled after each wait.
+  pub fn buffer_capacity(self, buffer_capacity: usize) -> EpollConfig {
+    EpollConfig { buffer_capacity: buffer_capacity, ..self }
+  }
+
+  pub fn stats(self, stats: bool) -> EpollConfig {
+    EpollConfig { stats: stats, ..self }
+  }
+
+  pub fn backend(self, backend: Backend) -> EpollConfig {
+    EpollConfig { backend: backend, ..self }
+  }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -193,6 +452,7 @@ mod tests {
     let config = EpollConfig {
       loop_ms: 10,
       buffer_capacity: 100,
+      ..Default::default()
     };
 
     let mut poll = Epoll::new_with(config, |_| ChannelHandler { tx: tx, state: EpollCmd::Poll }).unwrap();
@@ -208,11 +468,154 @@ mod tests {
 
     poll.epfd.register(rfd, &interest).unwrap();
 
-    poll.run_once();
+    poll.run_once().unwrap();
 
     let ev = rx.recv().unwrap();
+    let (events, data) = (ev.events, ev.data);
+
+    assert!(events.contains(EPOLLIN));
+    assert!(data == rfd as u64);
+  }
+
+  struct CountHandler {
+    count: usize,
+  }
+
+  impl Handler<EpollEvent, EpollCmd> for CountHandler {
+    fn next(&mut self) -> EpollCmd {
+      EpollCmd::Poll
+    }
+
+    fn on_next(&mut self, _: EpollEvent) {}
+  }
+
+  impl Handler<usize, ()> for CountHandler {
+    fn next(&mut self) {}
+
+    fn on_next(&mut self, n: usize) {
+      self.count += n;
+    }
+  }
+
+  #[test]
+  fn counts_events_per_wait() {
+    let config = EpollConfig {
+      loop_ms: 0,
+      buffer_capacity: 2,
+      stats: true,
+      ..Default::default()
+    };
+
+    let mut poll = Epoll::new_with(config, |_| CountHandler { count: 0 }).unwrap();
+
+    let pipes: Vec<_> = (0..3).map(|_| unistd::pipe2(O_NONBLOCK).unwrap()).collect();
+    for &(rfd, wfd) in &pipes {
+      unistd::write(wfd, b"hello!").unwrap();
+
+      let interest = EpollEvent {
+        events: EPOLLONESHOT | EPOLLIN,
+        data: rfd as u64,
+      };
+      poll.epfd.register(rfd, &interest).unwrap();
+    }
+
+    for _ in 0..3 {
+      poll.run_once().unwrap();
+    }
+
+    let stats = poll.counters().snapshot();
+    assert_eq!(stats.waits, 3);
+    assert_eq!(stats.events, 3);
+    assert_eq!(stats.max_batch, 2);
+    assert_eq!(stats.full_batches, 1);
+    assert_eq!(stats.buffer_capacity, 2);
+    assert_eq!(stats.events_per_wait(), 1.0);
+
+    for (rfd, wfd) in pipes {
+      unistd::close(rfd).unwrap();
+      unistd::close(wfd).unwrap();
+    }
+  }
+
+  #[test]
+  fn handle_runs_tasks_on_loop() {
+    let mut poll = Epoll::new_with(Default::default(), |_| CountHandler { count: 0 }).unwrap();
+    let handle = poll.handle().unwrap();
+
+    let t = ::std::thread::spawn(move || {
+      handle.spawn(|h| h.count += 1).unwrap();
+      handle.send(2_usize).unwrap();
+    });
+
+    t.join().unwrap();
+
+    // blocks in epoll_wait until woken up by the handle
+    poll.run_once().unwrap();
+
+    assert_eq!(poll.handler().count, 3);
+  }
+
+  #[test]
+  fn tasks_can_spawn_tasks() {
+    let mut poll = Epoll::new_with(Default::default(), |_| CountHandler { count: 0 }).unwrap();
+    let handle = poll.handle().unwrap();
+    let inner = handle.clone();
+
+    handle.spawn(move |h| {
+        h.count += 1;
+        inner.spawn(|h| h.count += 1).unwrap();
+      })
+      .unwrap();
+
+    poll.run_once().unwrap();
+
+    assert_eq!(poll.handler().count, 2);
+  }
+
+  /// Carries on after `errors` failures of the loop, then stops it.
+  struct FlakyHandler {
+    errors: usize,
+  }
+
+  impl Handler<EpollEvent, EpollCmd> for FlakyHandler {
+    fn next(&mut self) -> EpollCmd {
+      EpollCmd::Poll
+    }
+
+    fn on_next(&mut self, _: EpollEvent) {}
+
+    fn on_error(&mut self, error: Error) -> Result<()> {
+      if self.errors == 0 {
+        return Err(error);
+      }
+
+      self.errors -= 1;
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn handler_decides_on_errors() {
+    // not an epoll instance, epoll_wait fails with EINVAL
+    let (rfd, wfd) = unistd::pipe().unwrap();
+    let mut poll = Epoll::from_fd(EpollFd::new(rfd), FlakyHandler { errors: 2 }, Default::default());
+
+    assert!(poll.run().is_err());
+    assert_eq!(poll.handler().errors, 0);
+
+    unistd::close(wfd).unwrap();
+  }
+
+  #[test]
+  fn shutdown_after_grace_period() {
+    let mut poll = Epoll::new_with(Default::default(), |_| CountHandler { count: 0 }).unwrap();
+    let handle = poll.handle().unwrap();
+
+    let start = ::std::time::Instant::now();
+    handle.shutdown(::std::time::Duration::from_millis(20)).unwrap();
+
+    poll.run().unwrap();
 
-    assert!(ev.events.contains(EPOLLIN));
-    assert!(ev.data == rfd as u64);
+    assert!(start.elapsed() >= ::std::time::Duration::from_millis(20));
   }
 }
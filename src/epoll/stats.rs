@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of an event loop. Only the thread of the loop updates them, with
+/// plain loads and stores, so they cost a couple of clock reads per wait;
+/// `snapshot` can be called from any thread.
+#[derive(Debug, Default)]
+pub struct EpollCounters {
+  waits: AtomicU64,
+  events: AtomicU64,
+  wait_ns: AtomicU64,
+  handle_ns: AtomicU64,
+  max_batch: AtomicU64,
+  full_batches: AtomicU64,
+  buffer_capacity: AtomicU64,
+}
+
+/// Snapshot of the counters of an event loop since it was created.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EpollStats {
+  /// Calls to `epoll_wait` that returned, timeouts included
+  pub waits: u64,
+  /// Events returned by `epoll_wait`
+  pub events: u64,
+  /// Time spent blocked in `epoll_wait`
+  pub wait_time: Duration,
+  /// Time spent dispatching events to `Handler::on_next`
+  pub handle_time: Duration,
+  /// Most events returned by a single wait
+  pub max_batch: usize,
+  /// Waits that filled the whole event buffer, leaving ready events for the
+  /// next one; a high share means `EpollConfig::buffer_capacity` is too small
+  pub full_batches: u64,
+  pub buffer_capacity: usize,
+}
+
+impl EpollCounters {
+  #[inline]
+  pub fn record(&self, batch: usize, capacity: usize, wait: Duration, handle: Duration) {
+    add(&self.waits, 1);
+    add(&self.events, batch as u64);
+    add(&self.wait_ns, nanos(wait));
+    add(&self.handle_ns, nanos(handle));
+
+    if batch as u64 > self.max_batch.load(Ordering::Relaxed) {
+      self.max_batch.store(batch as u64, Ordering::Relaxed);
+    }
+
+    if batch == capacity {
+      add(&self.full_batches, 1);
+    }
+
+    self.buffer_capacity.store(capacity as u64, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> EpollStats {
+    EpollStats {
+      waits: self.waits.load(Ordering::Relaxed),
+      events: self.events.load(Ordering::Relaxed),
+      wait_time: Duration::from_nanos(self.wait_ns.load(Ordering::Relaxed)),
+      handle_time: Duration::from_nanos(self.handle_ns.load(Ordering::Relaxed)),
+      max_batch: self.max_batch.load(Ordering::Relaxed) as usize,
+      full_batches: self.full_batches.load(Ordering::Relaxed),
+      buffer_capacity: self.buffer_capacity.load(Ordering::Relaxed) as usize,
+    }
+  }
+}
+
+impl EpollStats {
+  pub fn events_per_wait(&self) -> f64 {
+    if self.waits == 0 {
+      return 0.0;
+    }
+    self.events as f64 / self.waits as f64
+  }
+
+  /// Share of the waits that filled the event buffer.
+  pub fn saturation(&self) -> f64 {
+    if self.waits == 0 {
+      return 0.0;
+    }
+    self.full_batches as f64 / self.waits as f64
+  }
+}
+
+/// Increment a counter only ever written by the calling thread.
+#[inline]
+pub(crate) fn add(counter: &AtomicU64, n: u64) {
+  counter.store(counter.load(Ordering::Relaxed).wrapping_add(n), Ordering::Relaxed);
+}
+
+#[inline]
+fn nanos(d: Duration) -> u64 {
+  d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
@@ -0,0 +1,886 @@
+//! io_uring backend of `Epoll`. Interests registered through the `EpollFd` of
+//! a ring become poll requests, whose completions are handed out as epoll
+//! events: edge-triggered interests are multishot requests, level-triggered
+//! ones are re-armed after their events are handled.
+//!
+//! Accepts, receives and sends submitted as `Op`s complete on the ring as well,
+//! with an event each whose result is kept until the handler takes it.
+
+use RawFd;
+use epoll::{EpollEvent, EpollEventKind, EPOLLIN, EPOLLOUT, EPOLLERR, EPOLLHUP, EPOLLET,
+            EPOLLONESHOT, EPOLLRDHUP};
+use error::*;
+use libc_sys::{self, c_void, MAP_FAILED, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
+use nix::Errno;
+use nix::sys::epoll::{EpollOp, EPOLLPRI};
+use nix::unistd;
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+mod sys;
+
+use self::sys::*;
+
+/// Submission queue entries of a ring; pending submissions only take one
+/// until the next wait
+const ENTRIES: u32 = 1024;
+
+/// `user_data` bit of poll removals, whose completions are ignored
+const REMOVE: u64 = 1 << 63;
+
+/// `user_data` bit of `Op`s, the rest being their slot
+const OP: u64 = 1 << 62;
+
+const SLOT_MASK: u64 = 0xffff_ffff;
+
+const GENERATION_MASK: u32 = 0x3fff_ffff;
+
+const SOCK_NONBLOCK: u32 = 0o4000;
+const SOCK_CLOEXEC: u32 = 0o2000000;
+const MSG_NOSIGNAL: u32 = 0x4000;
+
+/// Operation run by a ring, see `EpollFd::submit`. Buffers are held by the
+/// ring until the operation completes.
+pub enum Op {
+  /// Accept a connection on a listening socket, non-blocking and close-on-exec
+  Accept(RawFd),
+  /// Receive into the spare capacity of the buffer
+  Recv(RawFd, Vec<u8>),
+  /// Send the contents of the buffer
+  Send(RawFd, Vec<u8>),
+}
+
+/// Outcome of an `Op`: the socket accepted or the number of bytes received or
+/// sent, along with the buffer, extended by the bytes received.
+#[derive(Debug)]
+pub struct Completion {
+  pub res: ::std::result::Result<usize, Errno>,
+  pub buf: Option<Vec<u8>>,
+}
+
+pub struct Ring {
+  fd: RawFd,
+  state: Mutex<State>,
+  _rings: Mmap,
+  _sqes: Mmap,
+}
+
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+/// Submission and completion queues, and the poll requests of the interests.
+struct State {
+  sq_head: *const AtomicU32,
+  sq_tail: *const AtomicU32,
+  sq_mask: u32,
+  sq_entries: u32,
+  sq_array: *mut u32,
+  sqes: *mut io_uring_sqe,
+  cq_head: *const AtomicU32,
+  cq_tail: *const AtomicU32,
+  cq_mask: u32,
+  cqes: *const io_uring_cqe,
+  polls: Vec<Poll>,
+  free: Vec<usize>,
+  fds: HashMap<RawFd, usize>,
+  ops: Vec<Option<Pending>>,
+  free_ops: Vec<usize>,
+  /// Results by the `data` of their `Op`, `None` while it runs
+  done: HashMap<u64, Option<Completion>>,
+}
+
+/// `Op` submitted, with the event of its completion.
+struct Pending {
+  data: u64,
+  events: EpollEventKind,
+  buf: Option<Vec<u8>>,
+}
+
+struct Poll {
+  fd: RawFd,
+  events: EpollEventKind,
+  data: u64,
+  /// Told apart from the requests of earlier interests in the same slot
+  generation: u32,
+  live: bool,
+  armed: bool,
+}
+
+struct Mmap {
+  ptr: *mut u8,
+  len: usize,
+}
+
+impl Ring {
+  pub fn new() -> Result<Ring> {
+    let mut params = io_uring_params::default();
+    let fd = Errno::result(unsafe { io_uring_setup(ENTRIES, &mut params) })? as RawFd;
+
+    match Ring::map(fd, &params) {
+      Ok(ring) => Ok(ring),
+      Err(e) => {
+        let _ = unistd::close(fd);
+        Err(e)
+      }
+    }
+  }
+
+  fn map(fd: RawFd, params: &io_uring_params) -> Result<Ring> {
+    let required = IORING_FEAT_SINGLE_MMAP | IORING_FEAT_NODROP | IORING_FEAT_EXT_ARG;
+    if params.features & required != required {
+      return Err("io_uring: the backend needs Linux 5.13 or later".into());
+    }
+
+    let sq_len = params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+    let cq_len = params.cq_off.cqes as usize +
+                 params.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+
+    let rings = Mmap::new(fd, cmp::max(sq_len, cq_len), IORING_OFF_SQ_RING)?;
+    let sqes = Mmap::new(fd,
+                         params.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+                         IORING_OFF_SQES)?;
+
+    let (sq, cq) = (&params.sq_off, &params.cq_off);
+
+    let state = unsafe {
+      State {
+        sq_head: rings.at(sq.head),
+        sq_tail: rings.at(sq.tail),
+        sq_mask: *rings.at::<u32>(sq.ring_mask),
+        sq_entries: *rings.at::<u32>(sq.ring_entries),
+        sq_array: rings.at::<u32>(sq.array) as *mut u32,
+        sqes: sqes.ptr as *mut io_uring_sqe,
+        cq_head: rings.at(cq.head),
+        cq_tail: rings.at(cq.tail),
+        cq_mask: *rings.at::<u32>(cq.ring_mask),
+        cqes: rings.at(cq.cqes),
+        polls: Vec::new(),
+        free: Vec::new(),
+        fds: HashMap::new(),
+        ops: Vec::new(),
+        free_ops: Vec::new(),
+        done: HashMap::new(),
+      }
+    };
+
+    Ok(Ring {
+      fd: fd,
+      state: Mutex::new(state),
+      _rings: rings,
+      _sqes: sqes,
+    })
+  }
+
+  #[inline]
+  pub fn fd(&self) -> RawFd {
+    self.fd
+  }
+
+  /// `epoll_ctl` on the ring: adding an interest arms a poll request for `fd`,
+  /// modifying it replaces the request and deleting it cancels the request.
+  /// Submits the requests straight away, as this may be called from another
+  /// thread than the loop's.
+  pub fn ctl(&self, op: EpollOp, fd: RawFd, interest: &EpollEvent) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+
+    match op {
+      EpollOp::EpollCtlAdd => {
+        if state.fds.contains_key(&fd) {
+          return Err(NixError::Sys(errno::EEXIST).into());
+        }
+
+        let i = state.insert(fd, interest);
+        state.arm(self.fd, i)?;
+      }
+      EpollOp::EpollCtlMod => {
+        let i = state.slot(fd)?;
+        state.disarm(self.fd, i)?;
+        state.polls[i].events = interest.events;
+        state.polls[i].data = interest.data;
+        state.arm(self.fd, i)?;
+      }
+      EpollOp::EpollCtlDel => {
+        let i = state.slot(fd)?;
+        state.disarm(self.fd, i)?;
+        state.remove(i);
+      }
+    }
+
+    state.submit(self.fd)
+  }
+
+  /// Queue `op`, submitted by the next `wait`.
+  pub fn submit(&self, op: Op, data: u64) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+
+    if state.done.contains_key(&data) {
+      return Err(format!("io_uring: operation {} not completed yet", data).into());
+    }
+
+    let (mut sqe, events, mut buf) = match op {
+      Op::Accept(fd) => {
+        let sqe = io_uring_sqe {
+          opcode: IORING_OP_ACCEPT,
+          fd: fd,
+          op_flags: SOCK_NONBLOCK | SOCK_CLOEXEC,
+          ..Default::default()
+        };
+        (sqe, EPOLLIN, None)
+      }
+      Op::Recv(fd, mut buf) => {
+        let sqe = io_uring_sqe {
+          opcode: IORING_OP_RECV,
+          fd: fd,
+          addr: unsafe { buf.as_mut_ptr().add(buf.len()) } as u64,
+          len: (buf.capacity() - buf.len()) as u32,
+          ..Default::default()
+        };
+        (sqe, EPOLLIN, Some(buf))
+      }
+      Op::Send(fd, buf) => {
+        let sqe = io_uring_sqe {
+          opcode: IORING_OP_SEND,
+          fd: fd,
+          addr: buf.as_ptr() as u64,
+          len: buf.len() as u32,
+          op_flags: MSG_NOSIGNAL,
+          ..Default::default()
+        };
+        (sqe, EPOLLOUT, Some(buf))
+      }
+    };
+
+    let i = match state.free_ops.pop() {
+      Some(i) => i,
+      None => {
+        state.ops.push(None);
+        state.ops.len() - 1
+      }
+    };
+
+    sqe.user_data = OP | i as u64;
+
+    if let Err(e) = state.push(self.fd, sqe) {
+      state.free_ops.push(i);
+      return Err(e);
+    }
+
+    state.ops[i] = Some(Pending {
+      data: data,
+      events: events,
+      buf: buf.take(),
+    });
+    state.done.insert(data, None);
+
+    Ok(())
+  }
+
+  /// Result of the `Op` submitted with `data`, if it completed.
+  pub fn completion(&self, data: u64) -> Option<Completion> {
+    let mut state = self.state.lock().unwrap();
+
+    match state.done.get(&data) {
+      Some(&Some(_)) => state.done.remove(&data).and_then(|c| c),
+      _ => None,
+    }
+  }
+
+  /// Submit the pending requests, wait up to `timeout` ms for completions
+  /// if there are none and move them to `buf` as events, up to its capacity.
+  /// Adds the requests to arm again once the events are handled to `rearm`.
+  pub fn wait(&self, buf: &mut Vec<EpollEvent>, timeout: isize, rearm: &mut Vec<u64>)
+              -> Result<()> {
+    let pending = self.state.lock().unwrap().pending();
+
+    let ts = kernel_timespec {
+      tv_sec: (timeout / 1000) as i64,
+      tv_nsec: (timeout % 1000) as i64 * 1_000_000,
+    };
+
+    let arg = io_uring_getevents_arg {
+      sigmask: 0,
+      sigmask_sz: 8,
+      pad: 0,
+      ts: if timeout < 0 { 0 } else { &ts as *const kernel_timespec as u64 },
+    };
+
+    let res = syscall!(Errno::result(unsafe {
+      io_uring_enter(self.fd,
+                     pending,
+                     1,
+                     IORING_ENTER_GETEVENTS | IORING_ENTER_EXT_ARG,
+                     &arg as *const io_uring_getevents_arg as *const c_void,
+                     mem::size_of::<io_uring_getevents_arg>())
+    }));
+
+    if let Err(e) = res {
+      match *e.kind() {
+        ErrorKind::NixError(NixError::Sys(errno::ETIME)) |
+        ErrorKind::NixError(NixError::Sys(errno::EBUSY)) => {}
+        _ => return Err(e),
+      }
+    }
+
+    self.state.lock().unwrap().reap(buf, rearm);
+
+    Ok(())
+  }
+
+  /// Arm the requests of level-triggered interests again, unless they were
+  /// modified or deleted in the meantime. Submitted by the next `wait`.
+  /// Requests that fail to arm are left in `rearm` for the next round,
+  /// the first error being returned once the others are armed.
+  pub fn rearm(&self, rearm: &mut Vec<u64>) -> Result<()> {
+    if rearm.is_empty() {
+      return Ok(());
+    }
+
+    let mut state = self.state.lock().unwrap();
+    let mut res = Ok(());
+
+    rearm.retain(|&token| {
+      let (i, generation) = decode(token);
+
+      let stale = match state.polls.get(i) {
+        Some(poll) => !poll.live || poll.generation != generation || poll.armed,
+        None => true,
+      };
+
+      if stale {
+        return false;
+      }
+
+      match state.arm(self.fd, i) {
+        Ok(()) => false,
+        Err(e) => {
+          if res.is_ok() {
+            res = Err(e);
+          }
+          true
+        }
+      }
+    });
+
+    res
+  }
+}
+
+impl Drop for Ring {
+  fn drop(&mut self) {
+    let _ = unistd::close(self.fd);
+
+    // the kernel may still write to the buffers of operations in flight
+    // while it tears the ring down
+    if let Ok(mut state) = self.state.lock() {
+      for pending in state.ops.drain(..).flatten() {
+        mem::forget(pending.buf);
+      }
+    }
+  }
+}
+
+impl ::std::fmt::Debug for Ring {
+  fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+    write!(fmt, "Ring {{ fd: {} }}", self.fd)
+  }
+}
+
+impl State {
+  fn insert(&mut self, fd: RawFd, interest: &EpollEvent) -> usize {
+    let poll = Poll {
+      fd: fd,
+      events: interest.events,
+      data: interest.data,
+      generation: 0,
+      live: true,
+      armed: false,
+    };
+
+    let i = match self.free.pop() {
+      Some(i) => {
+        let generation = self.polls[i].generation;
+        self.polls[i] = Poll { generation: generation, ..poll };
+        i
+      }
+      None => {
+        self.polls.push(poll);
+        self.polls.len() - 1
+      }
+    };
+
+    self.fds.insert(fd, i);
+    i
+  }
+
+  fn remove(&mut self, i: usize) {
+    self.polls[i].live = false;
+    self.fds.remove(&self.polls[i].fd);
+    self.free.push(i);
+  }
+
+  fn slot(&self, fd: RawFd) -> Result<usize> {
+    match self.fds.get(&fd) {
+      Some(&i) => Ok(i),
+      None => Err(NixError::Sys(errno::ENOENT).into()),
+    }
+  }
+
+  fn arm(&mut self, ring: RawFd, i: usize) -> Result<()> {
+    let (fd, events, generation) = {
+      let poll = &self.polls[i];
+      (poll.fd, poll.events, poll.generation)
+    };
+
+    let mask = events & (EPOLLIN | EPOLLPRI | EPOLLOUT | EPOLLRDHUP | EPOLLERR | EPOLLHUP);
+
+    let sqe = io_uring_sqe {
+      opcode: IORING_OP_POLL_ADD,
+      fd: fd,
+      len: if events.contains(EPOLLET) { IORING_POLL_ADD_MULTI } else { 0 },
+      op_flags: mask.bits(),
+      user_data: encode(i, generation),
+      ..Default::default()
+    };
+
+    self.push(ring, sqe)?;
+    self.polls[i].armed = true;
+
+    Ok(())
+  }
+
+  /// Cancel the request of interest `i`, if armed, and tell the completions
+  /// still to come apart from the next request's.
+  fn disarm(&mut self, ring: RawFd, i: usize) -> Result<()> {
+    let (armed, generation) = (self.polls[i].armed, self.polls[i].generation);
+
+    if armed {
+      let sqe = io_uring_sqe {
+        opcode: IORING_OP_POLL_REMOVE,
+        fd: -1,
+        addr: encode(i, generation),
+        user_data: REMOVE | encode(i, generation),
+        ..Default::default()
+      };
+
+      self.push(ring, sqe)?;
+    }
+
+    self.polls[i].armed = false;
+    self.polls[i].generation = generation.wrapping_add(1) & GENERATION_MASK;
+
+    Ok(())
+  }
+
+  /// Queue `sqe`, submitting the pending requests first if the queue is full.
+  fn push(&mut self, ring: RawFd, sqe: io_uring_sqe) -> Result<()> {
+    if !self.try_push(sqe) {
+      self.submit(ring)?;
+
+      if !self.try_push(sqe) {
+        return Err("io_uring: submission queue full".into());
+      }
+    }
+
+    Ok(())
+  }
+
+  fn try_push(&mut self, sqe: io_uring_sqe) -> bool {
+    unsafe {
+      let head = (*self.sq_head).load(Ordering::Acquire);
+      let tail = (*self.sq_tail).load(Ordering::Relaxed);
+
+      if tail.wrapping_sub(head) == self.sq_entries {
+        return false;
+      }
+
+      let idx = tail & self.sq_mask;
+      ptr::write(self.sqes.offset(idx as isize), sqe);
+      *self.sq_array.offset(idx as isize) = idx;
+
+      (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    true
+  }
+
+  fn pending(&self) -> u32 {
+    unsafe {
+      let tail = (*self.sq_tail).load(Ordering::Relaxed);
+      tail.wrapping_sub((*self.sq_head).load(Ordering::Acquire))
+    }
+  }
+
+  fn submit(&self, ring: RawFd) -> Result<()> {
+    let pending = self.pending();
+
+    if pending > 0 {
+      syscall!(Errno::result(unsafe { io_uring_enter(ring, pending, 0, 0, ptr::null(), 0) }))?;
+    }
+
+    Ok(())
+  }
+
+  fn pop(&mut self) -> Option<io_uring_cqe> {
+    unsafe {
+      let head = (*self.cq_head).load(Ordering::Relaxed);
+
+      if head == (*self.cq_tail).load(Ordering::Acquire) {
+        return None;
+      }
+
+      let cqe = ptr::read(self.cqes.offset((head & self.cq_mask) as isize));
+      (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+
+      Some(cqe)
+    }
+  }
+
+  fn reap(&mut self, buf: &mut Vec<EpollEvent>, rearm: &mut Vec<u64>) {
+    while buf.len() < buf.capacity() {
+      let cqe = match self.pop() {
+        Some(cqe) => cqe,
+        None => return,
+      };
+
+      if cqe.user_data & REMOVE != 0 {
+        continue;
+      }
+
+      if cqe.user_data & OP != 0 {
+        let event = self.complete((cqe.user_data & SLOT_MASK) as usize, cqe.res);
+        buf.extend(event);
+        continue;
+      }
+
+      let (i, generation) = decode(cqe.user_data);
+
+      let poll = match self.polls.get_mut(i) {
+        Some(poll) if poll.live && poll.generation == generation => poll,
+        // completion of a request cancelled since
+        _ => continue,
+      };
+
+      if cqe.flags & IORING_CQE_F_MORE == 0 {
+        poll.armed = false;
+
+        if !poll.events.contains(EPOLLONESHOT) {
+          rearm.push(cqe.user_data);
+        }
+      }
+
+      let events = if cqe.res >= 0 {
+        EpollEventKind::from_bits_truncate(cqe.res as u32)
+      } else {
+        debug!("io_uring: poll on fd {} failed with {:?}",
+               poll.fd,
+               Errno::from_i32(-cqe.res));
+        EPOLLERR
+      };
+
+      buf.push(EpollEvent {
+        events: events,
+        data: poll.data,
+      });
+    }
+  }
+}
+
+impl State {
+  /// Keep the result of the `Op` in slot `i`, for the event returned.
+  fn complete(&mut self, i: usize, res: i32) -> Option<EpollEvent> {
+    let pending = self.ops.get_mut(i).and_then(|op| op.take())?;
+
+    self.free_ops.push(i);
+
+    let res = if res >= 0 {
+      Ok(res as usize)
+    } else {
+      Err(Errno::from_i32(-res))
+    };
+
+    let mut buf = pending.buf;
+
+    if let (Ok(n), Some(ref mut buf), EPOLLIN) = (res, buf.as_mut(), pending.events) {
+      let len = buf.len() + n;
+      unsafe { buf.set_len(len) };
+    }
+
+    let events = if res.is_ok() { pending.events } else { EPOLLERR };
+
+    self.done.insert(pending.data,
+                     Some(Completion {
+                       res: res,
+                       buf: buf,
+                     }));
+
+    Some(EpollEvent {
+      events: events,
+      data: pending.data,
+    })
+  }
+}
+
+unsafe impl Send for State {}
+
+impl Mmap {
+  fn new(fd: RawFd, len: usize, offset: i64) -> Result<Mmap> {
+    let ptr = unsafe {
+      libc_sys::mmap(ptr::null_mut(),
+                     len,
+                     PROT_READ | PROT_WRITE,
+                     MAP_SHARED | MAP_POPULATE,
+                     fd,
+                     offset)
+    };
+
+    if ptr == MAP_FAILED {
+      return Err(NixError::Sys(Errno::last()).into());
+    }
+
+    Ok(Mmap {
+      ptr: ptr as *mut u8,
+      len: len,
+    })
+  }
+
+  unsafe fn at<T>(&self, offset: u32) -> *const T {
+    self.ptr.offset(offset as isize) as *const T
+  }
+}
+
+impl Drop for Mmap {
+  fn drop(&mut self) {
+    unsafe {
+      libc_sys::munmap(self.ptr as *mut c_void, self.len);
+    }
+  }
+}
+
+#[inline]
+fn encode(i: usize, generation: u32) -> u64 {
+  ((generation as u64) << 32) | i as u64
+}
+
+#[inline]
+fn decode(token: u64) -> (usize, u32) {
+  ((token & SLOT_MASK) as usize, ((token & !REMOVE) >> 32) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+  use epoll::*;
+  use handler::Handler;
+  use nix::fcntl::O_NONBLOCK;
+  use nix::unistd;
+  use std::io::{Read, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::os::unix::io::AsRawFd;
+  use std::thread;
+  use std::time::{Duration, Instant};
+  use super::*;
+
+  struct Collect {
+    events: Vec<(u64, EpollEventKind)>,
+    tasks: usize,
+  }
+
+  impl Handler<EpollEvent, EpollCmd> for Collect {
+    fn next(&mut self) -> EpollCmd {
+      EpollCmd::Poll
+    }
+
+    fn on_next(&mut self, event: EpollEvent) {
+      self.events.push((event.data, event.events));
+    }
+  }
+
+  fn new_loop(loop_ms: isize) -> Epoll<Collect> {
+    let config = EpollConfig {
+      loop_ms: loop_ms,
+      backend: Backend::Uring,
+      ..Default::default()
+    };
+
+    Epoll::new_with(config, |_| {
+        Collect {
+          events: Vec::new(),
+          tasks: 0,
+        }
+      })
+      .unwrap()
+  }
+
+  /// Events of a round of the loop.
+  fn poll(epoll: &mut Epoll<Collect>) -> Vec<(u64, EpollEventKind)> {
+    epoll.run_once().unwrap();
+    epoll.handler_mut().events.drain(..).collect()
+  }
+
+  fn register(epoll: &Epoll<Collect>, fd: i32, events: EpollEventKind) {
+    let interest = EpollEvent {
+      events: events,
+      data: fd as u64,
+    };
+    epoll.epfd.register(fd, &interest).unwrap();
+  }
+
+  #[test]
+  fn rearms_level_triggered_interests() {
+    let mut epoll = new_loop(0);
+    let (rfd, wfd) = unistd::pipe2(O_NONBLOCK).unwrap();
+
+    register(&epoll, rfd, EPOLLIN);
+    assert!(epoll.epfd.register(rfd, &EpollEvent { events: EPOLLIN, data: 0 }).is_err());
+    assert_eq!(poll(&mut epoll), vec![]);
+
+    unistd::write(wfd, b"hello!").unwrap();
+    assert_eq!(poll(&mut epoll), vec![(rfd as u64, EPOLLIN)]);
+    // still readable
+    assert_eq!(poll(&mut epoll), vec![(rfd as u64, EPOLLIN)]);
+
+    unistd::read(rfd, &mut [0; 6]).unwrap();
+    assert_eq!(poll(&mut epoll), vec![]);
+
+    epoll.epfd.unregister(rfd).unwrap();
+    unistd::write(wfd, b"hello!").unwrap();
+    assert_eq!(poll(&mut epoll), vec![]);
+
+    unistd::close(rfd).unwrap();
+    unistd::close(wfd).unwrap();
+  }
+
+  #[test]
+  fn edge_triggered_interests_stay_armed() {
+    let mut epoll = new_loop(0);
+    let (rfd, wfd) = unistd::pipe2(O_NONBLOCK).unwrap();
+
+    register(&epoll, rfd, EPOLLIN | EPOLLET);
+
+    unistd::write(wfd, b"hello!").unwrap();
+    assert_eq!(poll(&mut epoll), vec![(rfd as u64, EPOLLIN)]);
+    assert_eq!(poll(&mut epoll), vec![]);
+
+    unistd::write(wfd, b"hello!").unwrap();
+    assert_eq!(poll(&mut epoll), vec![(rfd as u64, EPOLLIN)]);
+
+    unistd::close(rfd).unwrap();
+    unistd::close(wfd).unwrap();
+  }
+
+  #[test]
+  fn oneshot_interests_wait_to_be_modified() {
+    let mut epoll = new_loop(0);
+    let (rfd, wfd) = unistd::pipe2(O_NONBLOCK).unwrap();
+
+    register(&epoll, rfd, EPOLLIN | EPOLLONESHOT);
+
+    unistd::write(wfd, b"hello!").unwrap();
+    assert_eq!(poll(&mut epoll), vec![(rfd as u64, EPOLLIN)]);
+    assert_eq!(poll(&mut epoll), vec![]);
+
+    let interest = EpollEvent {
+      events: EPOLLIN | EPOLLONESHOT,
+      data: 7,
+    };
+    epoll.epfd.reregister(rfd, &interest).unwrap();
+    assert_eq!(poll(&mut epoll), vec![(7, EPOLLIN)]);
+
+    unistd::close(rfd).unwrap();
+    unistd::close(wfd).unwrap();
+  }
+
+  #[test]
+  fn handle_wakes_up_the_loop() {
+    let mut epoll = new_loop(-1);
+    let handle = epoll.handle().unwrap();
+
+    thread::spawn(move || handle.spawn(|h| h.tasks += 1).unwrap()).join().unwrap();
+
+    // blocks until woken up by the handle
+    epoll.run_once().unwrap();
+    assert_eq!(epoll.handler().tasks, 1);
+  }
+
+  /// Events of the rounds of the loop until there are some.
+  fn poll_until_events(epoll: &mut Epoll<Collect>) -> Vec<(u64, EpollEventKind)> {
+    let start = Instant::now();
+
+    loop {
+      assert!(start.elapsed() < Duration::from_secs(5), "no events");
+
+      let events = poll(epoll);
+      if !events.is_empty() {
+        return events;
+      }
+    }
+  }
+
+  #[test]
+  fn completes_accept_recv_and_send() {
+    let mut epoll = new_loop(10);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    epoll.epfd.submit(Op::Accept(listener.as_raw_fd()), 1).unwrap();
+    assert!(epoll.epfd.submit(Op::Accept(listener.as_raw_fd()), 1).is_err());
+
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    assert_eq!(poll_until_events(&mut epoll), vec![(1, EPOLLIN)]);
+
+    let fd = epoll.epfd.completion(1).unwrap().res.unwrap() as i32;
+    assert!(epoll.epfd.completion(1).is_none());
+
+    let mut buf = Vec::with_capacity(16);
+    buf.extend_from_slice(b">");
+    epoll.epfd.submit(Op::Recv(fd, buf), 2).unwrap();
+    assert_eq!(poll(&mut epoll), vec![]);
+    assert!(epoll.epfd.completion(2).is_none());
+
+    client.write_all(b"hello!").unwrap();
+    assert_eq!(poll_until_events(&mut epoll), vec![(2, EPOLLIN)]);
+
+    let completion = epoll.epfd.completion(2).unwrap();
+    assert_eq!(completion.res, Ok(6));
+
+    let buf = completion.buf.unwrap();
+    assert_eq!(&buf[..], b">hello!");
+
+    epoll.epfd.submit(Op::Send(fd, buf), 3).unwrap();
+    assert_eq!(poll_until_events(&mut epoll), vec![(3, EPOLLOUT)]);
+    assert_eq!(epoll.epfd.completion(3).unwrap().res, Ok(7));
+
+    let mut echoed = [0; 7];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b">hello!");
+
+    unistd::close(fd).unwrap();
+  }
+
+  #[test]
+  fn reports_failed_operations() {
+    let mut epoll = new_loop(10);
+    let (rfd, wfd) = unistd::pipe().unwrap();
+
+    // not a socket
+    epoll.epfd.submit(Op::Send(wfd, b"hello!".to_vec()), 1).unwrap();
+    assert_eq!(poll_until_events(&mut epoll), vec![(1, EPOLLERR)]);
+
+    let completion = epoll.epfd.completion(1).unwrap();
+    assert_eq!(completion.res, Err(Errno::ENOTSOCK));
+    assert_eq!(completion.buf.unwrap(), b"hello!");
+
+    unistd::close(rfd).unwrap();
+    unistd::close(wfd).unwrap();
+  }
+
+  #[test]
+  fn operations_need_a_ring() {
+    let epfd = EpollFd::create(Backend::Epoll).unwrap();
+    assert!(epfd.submit(Op::Accept(0), 1).is_err());
+    assert!(epfd.completion(1).is_none());
+    unistd::close(epfd.fd).unwrap();
+  }
+}
@@ -0,0 +1,123 @@
+//! The parts of the io_uring ABI the backend uses, from `linux/io_uring.h`.
+
+use libc_sys::{c_long, c_void};
+
+const SYS_IO_URING_SETUP: c_long = 425;
+const SYS_IO_URING_ENTER: c_long = 426;
+
+pub const IORING_OFF_SQ_RING: i64 = 0;
+pub const IORING_OFF_SQES: i64 = 0x10000000;
+
+pub const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+pub const IORING_FEAT_NODROP: u32 = 1 << 1;
+pub const IORING_FEAT_EXT_ARG: u32 = 1 << 8;
+
+pub const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+pub const IORING_ENTER_EXT_ARG: u32 = 1 << 3;
+
+pub const IORING_OP_POLL_ADD: u8 = 6;
+pub const IORING_OP_POLL_REMOVE: u8 = 7;
+pub const IORING_OP_ACCEPT: u8 = 13;
+pub const IORING_OP_SEND: u8 = 26;
+pub const IORING_OP_RECV: u8 = 27;
+
+/// `len` of a `IORING_OP_POLL_ADD` staying armed after it completes
+pub const IORING_POLL_ADD_MULTI: u32 = 1 << 0;
+
+/// The request stays armed and will complete again
+pub const IORING_CQE_F_MORE: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct io_sqring_offsets {
+  pub head: u32,
+  pub tail: u32,
+  pub ring_mask: u32,
+  pub ring_entries: u32,
+  pub flags: u32,
+  pub dropped: u32,
+  pub array: u32,
+  pub resv1: u32,
+  pub user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct io_cqring_offsets {
+  pub head: u32,
+  pub tail: u32,
+  pub ring_mask: u32,
+  pub ring_entries: u32,
+  pub overflow: u32,
+  pub cqes: u32,
+  pub flags: u32,
+  pub resv1: u32,
+  pub user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct io_uring_params {
+  pub sq_entries: u32,
+  pub cq_entries: u32,
+  pub flags: u32,
+  pub sq_thread_cpu: u32,
+  pub sq_thread_idle: u32,
+  pub features: u32,
+  pub wq_fd: u32,
+  pub resv: [u32; 3],
+  pub sq_off: io_sqring_offsets,
+  pub cq_off: io_cqring_offsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct io_uring_sqe {
+  pub opcode: u8,
+  pub flags: u8,
+  pub ioprio: u16,
+  pub fd: i32,
+  pub off: u64,
+  pub addr: u64,
+  pub len: u32,
+  /// `poll32_events`, `msg_flags`, `accept_flags`, ... depending on `opcode`
+  pub op_flags: u32,
+  pub user_data: u64,
+  pub buf_index: u16,
+  pub personality: u16,
+  pub splice_fd_in: i32,
+  pub addr3: u64,
+  pub pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct io_uring_cqe {
+  pub user_data: u64,
+  pub res: i32,
+  pub flags: u32,
+}
+
+#[repr(C)]
+pub struct io_uring_getevents_arg {
+  pub sigmask: u64,
+  pub sigmask_sz: u32,
+  pub pad: u32,
+  pub ts: u64,
+}
+
+#[repr(C)]
+pub struct kernel_timespec {
+  pub tv_sec: i64,
+  pub tv_nsec: i64,
+}
+
+pub unsafe fn io_uring_setup(entries: u32, params: *mut io_uring_params) -> c_long {
+  ::libc_sys::syscall(SYS_IO_URING_SETUP, entries, params)
+}
+
+pub unsafe fn io_uring_enter(fd: i32, to_submit: u32, min_complete: u32, flags: u32,
+                             arg: *const c_void, argsz: usize)
+                             -> c_long {
+  ::libc_sys::syscall(SYS_IO_URING_ENTER, fd, to_submit, min_complete, flags, arg, argsz)
+}
@@ -1,6 +1,14 @@
+use error::{Error, Result};
+
 pub trait Handler<In, Out> {
 
   fn on_next(&mut self, In);
 
   fn next(&mut self) -> Out;
+
+  /// The loop driving the handler failed. Returning `Ok` carries on with
+  /// the loop, returning an error stops it with that error.
+  fn on_error(&mut self, error: Error) -> Result<()> {
+    Err(error)
+  }
 }
@@ -16,7 +16,9 @@ pub mod error;
 pub mod macros;
 pub mod handler;
 pub mod mux;
+pub mod datagram;
 pub mod epoll;
+pub mod timer;
 pub mod buf;
 pub mod prop;
 pub mod daemon;
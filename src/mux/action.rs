@@ -1,39 +1,85 @@
 use RawFd;
 
+/// Epoll data layout:
+///
+/// ```text
+/// Notify: | fd: 32 | generation: 10 | slot: 20 | 00 |
+/// New:    | fd: 32 |          listener: 30    | 01 |
+/// Tick:   |                  0: 62            | 10 |
+/// ```
+///
+/// Tag `11` is never produced so that `u64::MAX` stays free for
+/// `Epoll`'s own wakeup event.
 pub enum Action {
-  Notify(usize, RawFd),
-  New(u64),
+  Notify(usize, u16, RawFd),
+  /// Listening socket and the index of the listener it belongs to
+  New(RawFd, usize),
+  Tick,
 }
 
+const TAG_BITS: u64 = 2;
+const TAG_MASK: u64 = (1 << TAG_BITS) - 1;
+const SLOT_BITS: u64 = 20;
+const GENERATION_BITS: u64 = 10;
+const LISTENER_BITS: u64 = 30;
+
+const NOTIFY: u64 = 0;
+const NEW: u64 = 1;
+const TICK: u64 = 2;
+
+/// Number of slots addressable by a token.
+pub const MAX_SLOTS: usize = 1 << SLOT_BITS;
+
+pub const GENERATION_MASK: u16 = (1 << GENERATION_BITS) - 1;
+
+/// Number of listeners addressable by a token.
+pub const MAX_LISTENERS: usize = 1 << LISTENER_BITS;
+
 impl Action {
   #[inline]
   pub fn encode(action: Action) -> u64 {
     match action {
-      Action::Notify(data, fd) => ((fd as u64) << 31) | ((data as u64) << 15),
-      Action::New(data) => data,
+      Action::Notify(slot, generation, fd) => {
+        debug_assert!(slot < MAX_SLOTS);
+        ((fd as u32 as u64) << 32) | (((generation & GENERATION_MASK) as u64) << (SLOT_BITS + TAG_BITS)) |
+        ((slot as u64) << TAG_BITS) | NOTIFY
+      }
+      Action::New(fd, listener) => {
+        debug_assert!(listener < MAX_LISTENERS);
+        ((fd as u32 as u64) << 32) | ((listener as u64) << TAG_BITS) | NEW
+      }
+      Action::Tick => TICK,
     }
   }
 
   #[inline]
   pub fn decode(data: u64) -> Action {
-    let arg1 = ((data >> 15) & 0xffff) as usize;
-    let fd = (data >> 31) as i32;
-    match data & 0x7fff {
-      0 => Action::Notify(arg1, fd),
-      _ => Action::New(data),
+    let fd = (data >> 32) as u32 as RawFd;
+    match data & TAG_MASK {
+      NOTIFY => {
+        let slot = ((data >> TAG_BITS) & (MAX_SLOTS as u64 - 1)) as usize;
+        let generation = (data >> (SLOT_BITS + TAG_BITS)) as u16 & GENERATION_MASK;
+        Action::Notify(slot, generation, fd)
+      }
+      NEW => {
+        let listener = ((data >> TAG_BITS) & (MAX_LISTENERS as u64 - 1)) as usize;
+        Action::New(fd, listener)
+      }
+      _ => Action::Tick,
     }
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use super::Action;
+  use super::*;
   #[test]
   fn decode_encode_new_action() {
-    let data = Action::encode(Action::New(::std::u64::MAX));
+    let data = Action::encode(Action::New(i32::MAX, MAX_LISTENERS - 1));
 
-    if let Action::New(fd) = Action::decode(data) {
-      assert!(fd == ::std::u64::MAX);
+    if let Action::New(fd, listener) = Action::decode(data) {
+      assert!(fd == i32::MAX);
+      assert!(listener == MAX_LISTENERS - 1);
     } else {
       panic!("action is not Action::New")
     }
@@ -41,13 +87,39 @@ mod tests {
 
   #[test]
   fn decode_encode_notify_action() {
-    let data = Action::encode(Action::Notify(10110, 0));
+    let data = Action::encode(Action::Notify(10110, 0, 0));
 
-    if let Action::Notify(data, fd) = Action::decode(data) {
+    if let Action::Notify(data, generation, fd) = Action::decode(data) {
       assert!(data == 10110);
+      assert!(generation == 0);
       assert!(fd == 0);
     } else {
       panic!("action is not Action::Notify")
     }
   }
+
+  #[test]
+  fn decode_encode_notify_generation() {
+    let data = Action::encode(Action::Notify(MAX_SLOTS - 1, GENERATION_MASK, i32::MAX));
+
+    if let Action::Notify(slot, generation, fd) = Action::decode(data) {
+      assert_eq!(slot, MAX_SLOTS - 1);
+      assert_eq!(generation, GENERATION_MASK);
+      assert_eq!(fd, i32::MAX);
+    } else {
+      panic!("action is not Action::Notify")
+    }
+  }
+
+  #[test]
+  fn decode_encode_tick_action() {
+    let data = Action::encode(Action::Tick);
+
+    assert!(data != u64::MAX);
+
+    if let Action::Tick = Action::decode(data) {
+    } else {
+      panic!("action is not Action::Tick")
+    }
+  }
 }
@@ -0,0 +1,33 @@
+/// What a `SyncMux` does with new connections when it has reached `MuxConfig::max_conn`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overflow {
+  /// Leave connections in the listener's backlog until a slot is freed
+  Backlog,
+  /// Accept and close them straight away
+  Close,
+  /// Accept, send the given bytes and close them
+  Reject(&'static [u8]),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MuxConfig {
+  /// Connection slots allocated up front; more are allocated on demand
+  pub reserve: usize,
+  /// Hard ceiling on concurrent connections per mux, or `None` for no ceiling
+  pub max_conn: Option<usize>,
+  pub overflow: Overflow,
+  /// Most connections accepted at once when a listener is ready; the others
+  /// wait for the next round of events
+  pub accept_batch: usize,
+}
+
+impl Default for MuxConfig {
+  fn default() -> MuxConfig {
+    MuxConfig {
+      reserve: 1024,
+      max_conn: None,
+      overflow: Overflow::Backlog,
+      accept_batch: 64,
+    }
+  }
+}
@@ -0,0 +1,194 @@
+use RawFd;
+use epoll::*;
+use error::*;
+use std::time::Duration;
+use mux::action::Action;
+use timer::{ClockId, TimerFd, TimerSpec, TimingWheel};
+
+/// Resolution of connection deadlines.
+pub const DEADLINE_TICK_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deadline {
+  Idle,
+  Read,
+  Write,
+}
+
+impl Deadline {
+  #[inline]
+  fn index(self) -> usize {
+    match self {
+      Deadline::Idle => 0,
+      Deadline::Read => 1,
+      Deadline::Write => 2,
+    }
+  }
+}
+
+/// Per-connection deadlines of a `SyncMux`, all driven by a single
+/// timerfd that ticks only while at least one deadline is armed.
+#[derive(Debug)]
+pub struct DeadlineWheel {
+  timer: Option<TimerFd>,
+  ticking: bool,
+  wheel: TimingWheel<(usize, u16, RawFd, Deadline)>,
+  slots: Vec<[Option<usize>; 3]>,
+}
+
+impl DeadlineWheel {
+  pub fn new(capacity: usize) -> DeadlineWheel {
+    DeadlineWheel {
+      timer: None,
+      ticking: false,
+      wheel: TimingWheel::with_capacity(capacity),
+      slots: vec![[None; 3]; capacity],
+    }
+  }
+
+  pub fn reserve(&mut self, additional: usize) {
+    let capacity = self.slots.len() + additional;
+    self.slots.resize(capacity, [None; 3]);
+  }
+
+  #[inline]
+  pub fn slot(&mut self, i: usize, generation: u16, fd: RawFd) -> Deadlines<'_> {
+    Deadlines {
+      wheel: self,
+      index: i,
+      generation: generation,
+      fd: fd,
+    }
+  }
+
+  /// Cancel all deadlines of the connection in slot `i`.
+  pub fn clear(&mut self, i: usize) {
+    for key in self.slots[i].iter_mut() {
+      if let Some(key) = key.take() {
+        self.wheel.cancel(key);
+      }
+    }
+  }
+
+  /// Read the timer's expirations and collect the deadlines that came due.
+  pub fn tick(&mut self, expired: &mut Vec<(usize, u16, RawFd, Deadline)>) -> Result<()> {
+    let ticks = match self.timer {
+      Some(ref tfd) => tfd.read()?.unwrap_or(0),
+      None => 0,
+    };
+
+    self.wheel.advance(ticks, expired);
+
+    for &(i, _, _, deadline) in expired.iter() {
+      self.slots[i][deadline.index()] = None;
+    }
+
+    Ok(())
+  }
+
+  /// Start or stop the timer depending on whether any deadline is armed.
+  pub fn sync(&mut self, epfd: &EpollFd) -> Result<()> {
+    if self.ticking != self.wheel.is_empty() {
+      return Ok(());
+    }
+
+    if self.timer.is_none() {
+      let tfd = TimerFd::new(ClockId::Monotonic)?;
+
+      let interest = EpollEvent {
+        events: EPOLLIN,
+        data: Action::encode(Action::Tick),
+      };
+
+      epfd.register(tfd.fd, &interest)?;
+      self.timer = Some(tfd);
+    }
+
+    let tfd = self.timer.as_ref().unwrap();
+
+    if self.ticking {
+      tfd.unset()?;
+    } else {
+      let tick = Duration::from_millis(DEADLINE_TICK_MS);
+      tfd.set(TimerSpec::Periodic {
+          delay: tick,
+          interval: tick,
+        })?;
+    }
+
+    self.ticking = !self.ticking;
+
+    Ok(())
+  }
+}
+
+/// Deadlines of the connection that received a `MuxEvent`.
+///
+/// When an armed deadline expires, the connection's handler receives
+/// a `MuxEvent` of kind `MuxEventKind::Deadline`.
+pub struct Deadlines<'r> {
+  wheel: &'r mut DeadlineWheel,
+  index: usize,
+  generation: u16,
+  fd: RawFd,
+}
+
+impl<'r> Deadlines<'r> {
+  /// Arm `deadline` to expire after `timeout`, rounded up to the next
+  /// `DEADLINE_TICK_MS` and one tick more, replacing it if it was already armed.
+  pub fn arm(&mut self, deadline: Deadline, timeout: Duration) {
+    self.cancel(deadline);
+
+    // the tick in progress, already partly elapsed, counts as a whole one
+    let ms = timeout.as_secs() * 1000 + (timeout.subsec_nanos() as u64 + 999_999) / 1_000_000;
+    let ticks = (ms + DEADLINE_TICK_MS - 1) / DEADLINE_TICK_MS + 1;
+
+    let key = self.wheel.wheel.insert(ticks, (self.index, self.generation, self.fd, deadline));
+    self.wheel.slots[self.index][deadline.index()] = Some(key);
+  }
+
+  pub fn cancel(&mut self, deadline: Deadline) {
+    if let Some(key) = self.wheel.slots[self.index][deadline.index()].take() {
+      self.wheel.wheel.cancel(key);
+    }
+  }
+
+  pub fn is_armed(&self, deadline: Deadline) -> bool {
+    self.wheel.slots[self.index][deadline.index()].is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::Instant;
+  use super::*;
+
+  #[test]
+  fn never_expires_early() {
+    let epfd = EpollFd::create(Backend::Epoll).unwrap();
+    let mut wheel = DeadlineWheel::new(2);
+    let mut expired = Vec::new();
+
+    // start the timer ticking
+    wheel.slot(0, 0, 10).arm(Deadline::Idle, Duration::from_secs(60));
+    wheel.sync(&epfd).unwrap();
+
+    // arm in the middle of a tick
+    thread::sleep(Duration::from_millis(DEADLINE_TICK_MS / 2));
+    let timeout = Duration::from_millis(2 * DEADLINE_TICK_MS);
+    let start = Instant::now();
+    wheel.slot(1, 0, 11).arm(Deadline::Read, timeout);
+
+    while expired.is_empty() {
+      thread::sleep(Duration::from_millis(1));
+      wheel.tick(&mut expired).unwrap();
+      assert!(start.elapsed() < Duration::from_secs(5), "deadline did not expire");
+    }
+
+    assert!(start.elapsed() >= timeout, "expired after {:?}", start.elapsed());
+    assert_eq!(expired, vec![(1, 0, 11, Deadline::Read)]);
+
+    ::nix::unistd::close(epfd.fd).unwrap();
+  }
+}
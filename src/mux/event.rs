@@ -1,5 +1,8 @@
 use RawFd;
+use error::errno::Errno;
 use epoll::EpollEventKind;
+use mux::deadline::{Deadline, Deadlines};
+use mux::metrics::MuxCounters;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MuxCmd {
@@ -13,8 +16,27 @@ impl Default for MuxCmd {
   }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxEventKind {
+  /// Readiness notification; see `MuxEvent::events`
+  Io,
+  /// An armed deadline expired; `MuxEvent::events` is empty
+  Deadline(Deadline),
+  /// First event of a connection opened with `SyncMux::connect`
+  Connected,
+  /// Outbound connection failed with the given error; the connection is
+  /// closed after the handler receives this event
+  ConnectFailed(Errno),
+}
+
 pub struct MuxEvent<'r, R: 'r> {
   pub resource: &'r mut R,
   pub events: EpollEventKind,
   pub fd: RawFd,
+  pub kind: MuxEventKind,
+  /// Listener the connection was accepted on, `None` for outbound connections
+  pub listener: Option<usize>,
+  pub deadlines: Deadlines<'r>,
+  /// Counters of the mux, for the handler to report the bytes it transfers
+  pub counters: &'r MuxCounters,
 }
@@ -1,7 +1,12 @@
 use RawFd;
 use epoll::EpollFd;
+use error::*;
 use handler::Handler;
+use libc_sys::{c_void, getsockopt, gid_t, pid_t, socklen_t, ucred, uid_t, SOL_SOCKET,
+               SO_PEERCRED};
 use mux::{MuxCmd, MuxEvent};
+use nix::Errno;
+use std::mem;
 
 pub trait HandlerFactory<'a, H, R>
   where H: Handler<MuxEvent<'a, R>, MuxCmd>,
@@ -10,3 +15,57 @@ pub trait HandlerFactory<'a, H, R>
   fn new_handler(&mut self, epfd: EpollFd, sockfd: RawFd) -> H;
   fn new_resource(&self) -> R;
 }
+
+/// Credentials of the process that connected to a unix socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCredentials {
+  pub pid: pid_t,
+  pub uid: uid_t,
+  pub gid: gid_t,
+}
+
+/// `SO_PEERCRED` of a connection accepted on a unix socket listener,
+/// for `HandlerFactory::new_handler` to authorize or tailor its handler.
+pub fn peer_credentials(sockfd: RawFd) -> Result<PeerCredentials> {
+  let mut cred: ucred = unsafe { mem::zeroed() };
+  let mut len = mem::size_of::<ucred>() as socklen_t;
+
+  unsafe {
+    Errno::result(getsockopt(sockfd,
+                             SOL_SOCKET,
+                             SO_PEERCRED,
+                             &mut cred as *mut ucred as *mut c_void,
+                             &mut len))?;
+  }
+
+  if len as usize != mem::size_of::<ucred>() {
+    return Err(format!("SO_PEERCRED: unexpected length {}", len).into());
+  }
+
+  Ok(PeerCredentials {
+    pid: cred.pid,
+    uid: cred.uid,
+    gid: cred.gid,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use nix::sys::socket::*;
+  use nix::unistd;
+  use super::*;
+
+  #[test]
+  fn reads_peer_credentials() {
+    let (a, b) = socketpair(AddressFamily::Unix, SockType::Stream, 0, SockFlag::empty()).unwrap();
+
+    let cred = peer_credentials(a).unwrap();
+
+    assert_eq!(cred.pid, unistd::getpid());
+    assert_eq!(cred.uid, unsafe { ::libc_sys::getuid() });
+    assert_eq!(cred.gid, unsafe { ::libc_sys::getgid() });
+
+    unistd::close(a).unwrap();
+    unistd::close(b).unwrap();
+  }
+}
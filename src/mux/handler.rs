@@ -1,19 +1,38 @@
+use RawFd;
 use Reset;
 use epoll::*;
 use error::*;
 use handler::*;
 use nix::sys::socket::*;
 use slab::Slab;
+use std::cmp;
+use std::sync::Arc;
 use super::*;
 use super::action::*;
+use super::deadline::DeadlineWheel;
+use super::metrics::MuxCounters;
 
 #[derive(Debug)]
 pub struct SyncMux<'m, H, P, R> {
   epfd: EpollFd,
   handlers: Slab<H, usize>,
   resources: Vec<R>,
-  factory: P,
+  /// Factory whose resource each slot holds
+  origins: Vec<usize>,
+  generations: Vec<u16>,
+  connecting: Vec<bool>,
+  listeners: Vec<Option<usize>>,
+  deadlines: DeadlineWheel,
+  expired: Vec<(usize, u16, RawFd, Deadline)>,
+  factories: Vec<P>,
+  /// Listeners unregistered while there are no free slots, with their ids
+  paused: Vec<(RawFd, usize)>,
+  config: MuxConfig,
   interests: EpollEventKind,
+  draining: bool,
+  metrics: MuxMetrics,
+  counters: Arc<MuxCounters>,
+  error_hook: Option<fn(Error) -> Result<()>>,
   _marker: ::std::marker::PhantomData<&'m ()>,
 }
 
@@ -22,16 +41,69 @@ impl<'m, H, P, R> SyncMux<'m, H, P, R>
         P: HandlerFactory<'m, H, R> + 'm,
         R: Clone + 'm,
 {
-  pub fn new(max_handlers: usize, epfd: EpollFd, factory: P) -> SyncMux<'m, H, P, R> {
+  /// Mux whose connections accepted on listener 0 and opened with `connect`
+  /// get their handlers and resources from `factory`.
+  ///
+  /// All factories are of type `P`: listeners with different factories are
+  /// served by an enum over them, as the handlers they make are of type `H`.
+  pub fn new(config: MuxConfig, epfd: EpollFd, factory: P) -> SyncMux<'m, H, P, R> {
+    let reserve = cmp::min(config.reserve, max_slots(&config));
+    let metrics = MuxMetrics::default();
+
     SyncMux {
       epfd: epfd,
-      handlers: Slab::with_capacity(max_handlers),
-      resources: vec!(factory.new_resource(); max_handlers),
-      factory: factory,
+      handlers: Slab::with_capacity(reserve),
+      resources: vec!(factory.new_resource(); reserve),
+      origins: vec!(0; reserve),
+      generations: vec!(0; reserve),
+      connecting: vec!(false; reserve),
+      listeners: vec!(None; reserve),
+      deadlines: DeadlineWheel::new(reserve),
+      expired: Vec::new(),
+      factories: vec!(factory),
+      paused: Vec::new(),
+      config: config,
       interests: H::interests(),
+      draining: false,
+      counters: metrics.register(),
+      metrics: metrics,
+      error_hook: None,
       _marker: ::std::marker::PhantomData {},
     }
   }
+
+  /// Get the handlers and resources of connections accepted on another listener
+  /// from `factory`. Returns the index of the listener, as in `Action::New`.
+  pub fn add_factory(&mut self, factory: P) -> usize {
+    self.factories.push(factory);
+    self.factories.len() - 1
+  }
+
+  /// Connection counters of this mux and of the ones cloned from it.
+  pub fn metrics(&self) -> MuxMetrics {
+    self.metrics.clone()
+  }
+
+  /// Decide with `hook` whether the loop carries on after it fails, as in
+  /// `Handler::on_error`, instead of stopping it.
+  pub fn on_loop_error(&mut self, hook: fn(Error) -> Result<()>) {
+    self.error_hook = Some(hook);
+  }
+}
+
+#[inline]
+fn close_reason(events: EpollEventKind, kind: MuxEventKind) -> CloseReason {
+  match kind {
+    MuxEventKind::ConnectFailed(_) => CloseReason::Error,
+    _ if events.contains(EPOLLERR) => CloseReason::Error,
+    _ if events.intersects(EPOLLHUP | EPOLLRDHUP) => CloseReason::Hangup,
+    _ => CloseReason::Close,
+  }
+}
+
+#[inline]
+fn max_slots(config: &MuxConfig) -> usize {
+  cmp::min(MAX_SLOTS, config.max_conn.unwrap_or(MAX_SLOTS))
 }
 
 macro_rules! some {
@@ -45,74 +117,412 @@ macro_rules! some {
   }}
 }
 
-impl<'m, H, P, R> Handler<EpollEvent, EpollCmd> for SyncMux<'m, H, P, R>
+impl<'m, H, P, R> SyncMux<'m, H, P, R>
   where H: Handler<MuxEvent<'m, R>, MuxCmd> + EpollHandler,
         P: HandlerFactory<'m, H, R> + 'm,
         R: Reset + Clone + 'm,
 {
-  #[inline(always)]
-  fn next(&mut self) -> EpollCmd {
-    EpollCmd::Poll
+  /// Apply a new configuration; connections over a lowered `max_conn`
+  /// are kept but no new ones are taken until enough of them close.
+  pub fn reconfigure(&mut self, config: MuxConfig) {
+    self.config = config;
+
+    // listeners paused under the old limit have room again
+    if self.handlers.len() < max_slots(&self.config) {
+      self.resume();
+    }
   }
 
-  fn on_next(&mut self, event: EpollEvent) {
+  #[inline]
+  fn dispatch(&mut self, i: usize, generation: u16, clifd: RawFd, events: EpollEventKind,
+              kind: MuxEventKind) {
+    // ignore outstanding event from removed handler
+    let mut entry = some!(self.handlers.entry(i));
 
-    match Action::decode(event.data) {
+    // ignore outstanding event from a handler that was removed and whose
+    // slot has been reused since the event was collected
+    if self.generations[i] != generation {
+      trace!("dispatch: dropping stale event for slot {} generation {}", i, generation);
+      return;
+    }
+
+    let resource = unsafe { &mut *(&mut self.resources[i] as *mut R) };
+    let deadlines = unsafe { &mut *(&mut self.deadlines as *mut DeadlineWheel) };
+    let counters = unsafe { &*(&*self.counters as *const MuxCounters) };
+
+    entry.get_mut().on_next(MuxEvent {
+      resource: resource,
+      events: events,
+      fd: clifd,
+      kind: kind,
+      listener: self.listeners[i],
+      deadlines: deadlines.slot(i, generation, clifd),
+      counters: counters,
+    });
+
+    let cmd = match kind {
+      MuxEventKind::ConnectFailed(_) => MuxCmd::Close,
+      _ => entry.get_mut().next(),
+    };
+
+    keep_or!(cmd, {
+      self.resources[i].reset();
+      self.deadlines.clear(i);
+      self.connecting[i] = false;
+      self.generations[i] = generation.wrapping_add(1) & GENERATION_MASK;
+      entry.remove();
+      self.counters.closed(close_reason(events, kind));
+      self.counters.active(self.handlers.len());
+      if let Err(e) = self.epfd.unregister(clifd) {
+        report_err!(e.into());
+      }
+      if let Err(e) = syscall!(::unistd::close(clifd)) {
+        report_err!(e.into());
+      }
+      self.resume();
+    });
+  }
+
+  fn on_tick(&mut self) {
+    let mut expired = ::std::mem::take(&mut self.expired);
+
+    if let Err(e) = self.deadlines.tick(&mut expired) {
+      report_err!(e);
+    }
+
+    for (i, generation, clifd, deadline) in expired.drain(..) {
+      self.dispatch(i,
+                    generation,
+                    clifd,
+                    EpollEventKind::empty(),
+                    MuxEventKind::Deadline(deadline));
+    }
+
+    self.expired = expired;
+  }
+
+  /// Make sure there is a vacant slot, growing the slab up to `max_conn`.
+  fn reserve(&mut self) -> bool {
+    let max_slots = max_slots(&self.config);
+
+    // max_conn may have been lowered below the slots already allocated
+    if self.handlers.len() >= max_slots {
+      return false;
+    }
+
+    if self.handlers.has_available() {
+      return true;
+    }
+
+    let capacity = self.handlers.capacity();
 
-      Action::Notify(i, clifd) => {
-        // ignore outstanding event from removed handler
-        let mut entry = some!(self.handlers.entry(i));
+    if capacity >= max_slots {
+      return false;
+    }
+
+    let additional = cmp::min(cmp::max(1, capacity), max_slots - capacity);
+
+    self.handlers.reserve_exact(additional);
+    self.resources.resize(capacity + additional, self.factories[0].new_resource());
+    self.origins.resize(capacity + additional, 0);
+    self.generations.resize(capacity + additional, 0);
+    self.connecting.resize(capacity + additional, false);
+    self.listeners.resize(capacity + additional, None);
+    self.deadlines.reserve(additional);
+
+    debug!("reserve: grew connection slots from {} to {}", capacity, capacity + additional);
+
+    true
+  }
+
+  /// Open a non-blocking outbound stream connection to `addr`, driven by a
+  /// handler from the factory in a slot shared with accepted connections.
+  ///
+  /// The handler's first event is `MuxEventKind::Connected` or
+  /// `MuxEventKind::ConnectFailed`.
+  pub fn connect(&mut self, addr: &SockAddr) -> Result<RawFd> {
+    if !self.reserve() {
+      return Err(format!("connect: {} connections open", self.handlers.len()).into());
+    }
+
+    let clifd = socket(addr.family(), SockType::Stream, SOCK_NONBLOCK | SOCK_CLOEXEC, 0)?;
+
+    match connect(clifd, addr) {
+      Ok(()) |
+      Err(NixError::Sys(errno::EINPROGRESS)) |
+      Err(NixError::Sys(errno::EINTR)) => {}
+      Err(e) => {
+        let _ = ::unistd::close(clifd);
+        return Err(e.into());
+      }
+    }
+
+    let entry = self.handlers.vacant_entry().unwrap();
+    let i = entry.index();
+
+    let event = EpollEvent {
+      events: self.interests | EPOLLOUT,
+      data: Action::encode(Action::Notify(i, self.generations[i], clifd)),
+    };
+
+    if let Err(e) = self.epfd.register(clifd, &event) {
+      let _ = ::unistd::close(clifd);
+      return Err(e);
+    }
+
+    debug!("connect: connecting tcp client {} to {}", &clifd, addr);
+
+    let h = self.factories[0].new_handler(self.epfd.clone(), clifd);
+    if self.origins[i] != 0 {
+      self.resources[i] = self.factories[0].new_resource();
+      self.origins[i] = 0;
+    }
+    self.connecting[i] = true;
+    self.listeners[i] = None;
+    entry.insert(h);
+    self.counters.active(self.handlers.len());
+
+    Ok(clifd)
+  }
+
+  /// Outcome of a pending `connect`, on the first event of its connection.
+  fn on_connect(&mut self, i: usize, generation: u16, clifd: RawFd) -> MuxEventKind {
+    self.connecting[i] = false;
+
+    let kind = match getsockopt(clifd, sockopt::SocketError) {
+      Ok(0) => MuxEventKind::Connected,
+      Ok(err) => MuxEventKind::ConnectFailed(errno::Errno::from_i32(err)),
+      Err(e) => MuxEventKind::ConnectFailed(e.errno()),
+    };
+
+    // stop the writable notifications requested for the connect
+    if let MuxEventKind::Connected = kind {
+      if !self.interests.contains(EPOLLOUT) {
+        let event = EpollEvent {
+          events: self.interests,
+          data: Action::encode(Action::Notify(i, generation, clifd)),
+        };
+
+        if let Err(e) = self.epfd.reregister(clifd, &event) {
+          report_err!(e);
+        }
+      }
+    }
+
+    kind
+  }
+
+  #[inline]
+  fn is_connecting(&self, i: usize, generation: u16) -> bool {
+    self.connecting.get(i).cloned().unwrap_or(false) && self.generations[i] == generation
+  }
+
+  /// Accept the connections waiting on `srvfd` until there are none left,
+  /// up to `MuxConfig::accept_batch` of them and as many as there are free slots.
+  fn accept(&mut self, srvfd: RawFd, listener: usize) {
+    if listener >= self.factories.len() {
+      error!("accept: no handler factory for listener {} of fd {}", listener, srvfd);
+
+      if let Ok(Some(clifd)) = syscall!(accept4(srvfd, SOCK_CLOEXEC)) {
+        if let Err(e) = syscall!(::unistd::close(clifd)) {
+          report_err!(e);
+        }
+      }
+      return;
+    }
+
+    for _ in 0..cmp::max(1, self.config.accept_batch) {
+      if !self.reserve() {
+        if self.overflow(srvfd, listener) {
+          continue;
+        }
+        return;
+      }
 
-        let resource = unsafe { &mut *(&mut self.resources[i] as *mut R) };
+      match syscall!(accept4(srvfd, SOCK_CLOEXEC | SOCK_NONBLOCK)) {
+        Ok(Some(clifd)) => {
+          debug!("accept: accepted new tcp client {}", &clifd);
+          let entry = self.handlers.vacant_entry().unwrap();
+          let i = entry.index();
 
-        entry.get_mut().on_next(MuxEvent {
-          resource: resource,
-          events: event.events,
-          fd: clifd,
-        });
+          let event = EpollEvent {
+            events: self.interests,
+            data: Action::encode(Action::Notify(i, self.generations[i], clifd)),
+          };
 
-        keep_or!(entry.get_mut().next(), {
-          self.resources[i].reset();
-          entry.remove();
-          if let Err(e) = self.epfd.unregister(clifd) {
-            report_err!(e.into());
+          if let Err(e) = self.epfd.register(clifd, &event) {
+            let _ = ::unistd::close(clifd);
+            self.accept_failed(&e);
+            report_err!(e);
+            return;
           }
-          if let Err(e) = syscall!(::unistd::close(clifd)) {
-            report_err!(e.into());
+
+          let h = self.factories[listener].new_handler(self.epfd.clone(), clifd);
+          // the slot's resource may come from another listener's factory
+          if self.origins[i] != listener {
+            self.resources[i] = self.factories[listener].new_resource();
+            self.origins[i] = listener;
           }
+          self.listeners[i] = Some(listener);
+
+          entry.insert(h);
+          self.counters.accepted();
+          self.counters.active(self.handlers.len());
+        }
+        Ok(None) => {
+          debug!("accept4: socket not ready");
           return;
-        });
+        }
+        Err(e) => {
+          self.accept_failed(&e);
+
+          match *e.kind() {
+            // the connection was reset while queued, the next one may be fine
+            ErrorKind::NixError(NixError::Sys(errno::ECONNABORTED)) |
+            ErrorKind::NixError(NixError::Sys(errno::EPROTO)) => {
+              debug!("accept4: connection aborted");
+            }
+            _ => {
+              report_err!(e);
+              return;
+            }
+          }
+        }
       }
+    }
+  }
+
+  /// Reject a connection waiting on `srvfd` according to `MuxConfig::overflow`.
+  /// Returns whether one was taken off the backlog.
+  fn overflow(&mut self, srvfd: RawFd, listener: usize) -> bool {
+    if let Overflow::Backlog = self.config.overflow {
+      trace!("accept: {} connections open, leaving new ones in backlog",
+             self.handlers.len());
+      self.pause(srvfd, listener);
+      return false;
+    }
 
-      Action::New(data) => {
-        let srvfd = data as i32;
+    match syscall!(accept4(srvfd, SOCK_CLOEXEC | SOCK_NONBLOCK)) {
+      Ok(Some(clifd)) => {
+        warn!("accept: {} connections open, rejecting new tcp client {}",
+              self.handlers.len(),
+              clifd);
 
-        // do not accept unless we have a vacant entry
-        // TODO grow slab, deprecate max_conn in favour of reserve slots
-        // or Mux::reserve to pre-allocate and then grow as it needs more
-        let entry = some!(self.handlers.vacant_entry());
+        if let Overflow::Reject(msg) = self.config.overflow {
+          if let Err(e) = syscall!(send(clifd, msg, MSG_DONTWAIT)) {
+            report_err!(e);
+          }
+        }
 
-        match syscall!(accept(srvfd)) {
-          Ok(Some(clifd)) => {
-            debug!("accept: accepted new tcp client {}", &clifd);
-            let i = entry.index();
+        if let Err(e) = syscall!(::unistd::close(clifd)) {
+          report_err!(e);
+        }
 
-            let h = self.factory.new_handler(self.epfd, clifd);
+        self.counters.rejected();
+        true
+      }
+      Ok(None) => {
+        debug!("accept4: socket not ready");
+        false
+      }
+      Err(e) => {
+        self.accept_failed(&e);
+        report_err!(e);
+        false
+      }
+    }
+  }
 
-            let event = EpollEvent {
-              events: self.interests,
-              data: Action::encode(Action::Notify(i, clifd)),
-            };
+  /// Stop polling a listener whose backlog is left alone, as it would stay
+  /// readable until a slot frees.
+  fn pause(&mut self, srvfd: RawFd, listener: usize) {
+    if let Err(e) = self.epfd.unregister(srvfd) {
+      report_err!(e);
+      return;
+    }
 
-            self.epfd.register(clifd, &event).unwrap();
+    debug!("accept: pausing listener {} of fd {}", listener, srvfd);
+    self.paused.push((srvfd, listener));
+  }
 
-            entry.insert(h);
-          }
-          Ok(None) => debug!("accept4: socket not ready"),
-          Err(e) => report_err!(e.into()),
+  /// Poll the paused listeners again, once there is a free slot.
+  fn resume(&mut self) {
+    if self.paused.is_empty() || self.draining {
+      return;
+    }
+
+    for (srvfd, listener) in self.paused.drain(..) {
+      let event = EpollEvent {
+        events: <Self as EpollHandler>::interests(),
+        data: Action::encode(Action::New(srvfd, listener)),
+      };
+
+      debug!("accept: resuming listener {} of fd {}", listener, srvfd);
+
+      if let Err(e) = self.epfd.register(srvfd, &event) {
+        report_err!(e);
+      }
+    }
+  }
+
+  fn accept_failed(&self, e: &Error) {
+    if let ErrorKind::NixError(NixError::Sys(errno)) = *e.kind() {
+      self.counters.accept_failed(errno);
+    }
+  }
+}
+
+impl<'m, H, P, R> Handler<EpollEvent, EpollCmd> for SyncMux<'m, H, P, R>
+  where H: Handler<MuxEvent<'m, R>, MuxCmd> + EpollHandler,
+        P: HandlerFactory<'m, H, R> + 'm,
+        R: Reset + Clone + 'm,
+{
+  #[inline(always)]
+  fn next(&mut self) -> EpollCmd {
+    if self.draining && self.handlers.is_empty() {
+      return EpollCmd::Shutdown;
+    }
+
+    EpollCmd::Poll
+  }
+
+  fn on_next(&mut self, event: EpollEvent) {
+
+    match Action::decode(event.data) {
+
+      Action::Notify(i, generation, clifd) => {
+        let kind = if self.is_connecting(i, generation) {
+          self.on_connect(i, generation, clifd)
+        } else {
+          MuxEventKind::Io
+        };
+
+        self.dispatch(i, generation, clifd, event.events, kind);
+      }
+
+      Action::New(srvfd, listener) => {
+        if !self.draining {
+          self.accept(srvfd, listener);
+        } else {
+          // outstanding event from a listener that was unregistered, or
+          // a paused listener resumed while the loop was told to drain
+          let _ = self.epfd.unregister(srvfd);
         }
       }
+
+      Action::Tick => self.on_tick(),
     };
+
+    if let Err(e) = self.deadlines.sync(&self.epfd) {
+      report_err!(e);
+    }
+  }
+
+  fn on_error(&mut self, error: Error) -> Result<()> {
+    match self.error_hook {
+      Some(hook) => hook(error),
+      None => Err(error),
+    }
   }
 }
 
@@ -125,6 +535,17 @@ impl<'m, H, P, R> EpollHandler for SyncMux<'m, H, P, R> {
   fn with_epfd(&mut self, epfd: EpollFd) {
     self.epfd = epfd;
   }
+
+  /// Stop accepting and shut down once all connections are closed.
+  /// Listeners must be unregistered by the caller.
+  fn drain(&mut self) {
+    self.draining = true;
+    self.paused.clear();
+  }
+
+  fn retire(&mut self, srvfd: RawFd) {
+    self.paused.retain(|&(fd, _)| fd != srvfd);
+  }
 }
 
 impl<'m, H, P, R> Clone for SyncMux<'m, H, P, R>
@@ -133,24 +554,579 @@ impl<'m, H, P, R> Clone for SyncMux<'m, H, P, R>
         R: Clone + 'm,
 {
   fn clone(&self) -> Self {
+    let reserve = cmp::min(self.config.reserve, max_slots(&self.config));
+
     SyncMux {
-      epfd: self.epfd,
-      handlers: Slab::with_capacity(self.handlers.capacity()),
-      resources: vec!(self.factory.new_resource(); self.handlers.capacity()),
-      factory: self.factory.clone(),
+      epfd: self.epfd.clone(),
+      handlers: Slab::with_capacity(reserve),
+      resources: vec!(self.factories[0].new_resource(); reserve),
+      origins: vec!(0; reserve),
+      generations: vec!(0; reserve),
+      connecting: vec!(false; reserve),
+      listeners: vec!(None; reserve),
+      deadlines: DeadlineWheel::new(reserve),
+      expired: Vec::new(),
+      factories: self.factories.clone(),
+      paused: Vec::new(),
+      config: self.config,
       interests: self.interests,
+      draining: self.draining,
+      metrics: self.metrics.clone(),
+      counters: self.metrics.register(),
+      error_hook: self.error_hook,
       _marker: ::std::marker::PhantomData {},
     }
   }
 }
 
+impl<'m, H, P, R> EpollHandle<SyncMux<'m, H, P, R>>
+  where H: Handler<MuxEvent<'m, R>, MuxCmd> + EpollHandler,
+        P: HandlerFactory<'m, H, R> + 'm,
+        R: Reset + Clone + 'm,
+{
+  /// `SyncMux::connect` on the loop's thread. Errors before the connection
+  /// is registered, such as running out of slots, are only logged.
+  pub fn connect(&self, addr: SockAddr) -> Result<()> {
+    self.spawn(move |mux| {
+      if let Err(e) = mux.connect(&addr) {
+        report_err!(e);
+      }
+    })
+  }
+}
+
 
 #[cfg(test)]
 mod tests {
+  use RawFd;
+  use Reset;
+  use epoll::*;
+  use handler::*;
+  use std::cell::RefCell;
+  use std::io::Read;
+  use std::net::{TcpListener, TcpStream};
+  use std::os::unix::io::AsRawFd;
+  use std::rc::Rc;
+  use std::time::{Duration, Instant};
   use super::*;
 
+  type Log = Rc<RefCell<Vec<(RawFd, MuxEventKind)>>>;
+
+  #[derive(Clone, Debug, PartialEq)]
+  struct TestResource(usize);
+
+  impl Reset for TestResource {
+    fn reset(&mut self) {}
+  }
+
+  struct TestHandler {
+    log: Log,
+    idle: Option<Duration>,
+    closed: bool,
+  }
+
+  impl<'a> Handler<MuxEvent<'a, TestResource>, MuxCmd> for TestHandler {
+    fn next(&mut self) -> MuxCmd {
+      if self.closed {
+        return MuxCmd::Close;
+      }
+
+      MuxCmd::Keep
+    }
+
+    fn on_next(&mut self, mut event: MuxEvent<'a, TestResource>) {
+      self.log.borrow_mut().push((event.fd, event.kind));
+
+      match event.kind {
+        MuxEventKind::Io => {
+          if event.events.intersects(EPOLLRDHUP | EPOLLHUP) {
+            self.closed = true;
+          } else if let Some(idle) = self.idle.take() {
+            event.deadlines.arm(Deadline::Idle, idle);
+          }
+        }
+        MuxEventKind::Deadline(_) => self.closed = true,
+        MuxEventKind::Connected |
+        MuxEventKind::ConnectFailed(_) => {}
+      }
+    }
+  }
+
+  impl EpollHandler for TestHandler {
+    fn interests() -> EpollEventKind {
+      EPOLLIN | EPOLLOUT | EPOLLRDHUP | EPOLLET
+    }
+
+    fn with_epfd(&mut self, _: EpollFd) {}
+  }
+
+  #[derive(Clone)]
+  struct TestFactory {
+    log: Log,
+    accepted: Rc<RefCell<Vec<RawFd>>>,
+    idle: Option<Duration>,
+    resource: usize,
+  }
+
+  impl<'a> HandlerFactory<'a, TestHandler, TestResource> for TestFactory {
+    fn new_handler(&mut self, _: EpollFd, sockfd: RawFd) -> TestHandler {
+      self.accepted.borrow_mut().push(sockfd);
+      TestHandler {
+        log: self.log.clone(),
+        idle: self.idle,
+        closed: false,
+      }
+    }
+
+    fn new_resource(&self) -> TestResource {
+      TestResource(self.resource)
+    }
+  }
+
+  fn new_mux(mux_config: MuxConfig, factory: TestFactory)
+             -> (Epoll<SyncMux<'static, TestHandler, TestFactory, TestResource>>, TcpListener) {
+    let config = EpollConfig {
+      loop_ms: 10,
+      buffer_capacity: 64,
+      ..Default::default()
+    };
+
+    let poll = Epoll::new_with(config, |epfd| SyncMux::new(mux_config, epfd, factory)).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+
+    let srvfd = listener.as_raw_fd();
+    let interest = EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::New(srvfd, 0)),
+    };
+
+    poll.epfd.register(srvfd, &interest).unwrap();
+
+    (poll, listener)
+  }
+
+  fn new_factory() -> (TestFactory, Log) {
+    let log: Log = Rc::new(RefCell::new(Vec::new()));
+    let factory = TestFactory {
+      log: log.clone(),
+      accepted: Rc::new(RefCell::new(Vec::new())),
+      idle: None,
+      resource: 0,
+    };
+
+    (factory, log)
+  }
+
+  fn connected(log: &Log) -> usize {
+    let mut fds: Vec<RawFd> = log.borrow().iter().map(|&(fd, _)| fd).collect();
+    fds.sort();
+    fds.dedup();
+    fds.len()
+  }
+
+  fn poll_until<F>(poll: &mut Epoll<SyncMux<'static, TestHandler, TestFactory, TestResource>>,
+                   cond: F)
+    where F: Fn() -> bool,
+  {
+    let start = Instant::now();
+    while !cond() {
+      assert!(start.elapsed() < Duration::from_secs(5), "condition not met");
+      poll.run_once().unwrap();
+    }
+  }
+
   #[test]
   fn should_grow_slab() {
-    // TODO assert!(false);
+    let (factory, log) = new_factory();
+    let config = MuxConfig {
+      reserve: 1,
+      ..Default::default()
+    };
+
+    let (mut poll, listener) = new_mux(config, factory);
+
+    let clients: Vec<TcpStream> = (0..5)
+      .map(|_| TcpStream::connect(listener.local_addr().unwrap()).unwrap())
+      .collect();
+
+    poll_until(&mut poll, || connected(&log) == clients.len());
+  }
+
+  fn assert_rejected(overflow: Overflow, expected: &[u8]) {
+    let (factory, log) = new_factory();
+    let config = MuxConfig {
+      reserve: 1,
+      max_conn: Some(1),
+      overflow: overflow,
+      ..Default::default()
+    };
+
+    let (mut poll, listener) = new_mux(config, factory);
+
+    let _first = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    poll_until(&mut poll, || connected(&log) == 1);
+
+    let mut second = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    for _ in 0..10 {
+      poll.run_once().unwrap();
+    }
+
+    let mut buf = Vec::new();
+    second.read_to_end(&mut buf).unwrap();
+
+    assert_eq!(&buf[..], expected);
+    assert_eq!(connected(&log), 1);
+
+    let stats = poll.handler().metrics().snapshot();
+    assert_eq!((stats.accepts, stats.rejected, stats.active), (1, 1, 1));
+  }
+
+  #[test]
+  fn overflow_close() {
+    assert_rejected(Overflow::Close, b"");
+  }
+
+  #[test]
+  fn overflow_reject() {
+    assert_rejected(Overflow::Reject(b"busy"), b"busy");
+  }
+
+  #[test]
+  fn overflow_backlog() {
+    let (factory, log) = new_factory();
+    let config = MuxConfig {
+      reserve: 1,
+      max_conn: Some(1),
+      overflow: Overflow::Backlog,
+      ..Default::default()
+    };
+
+    let (mut poll, listener) = new_mux(config, factory);
+
+    let first = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    poll_until(&mut poll, || connected(&log) == 1);
+
+    let _second = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    // the listener is left out of the wait while full, so each round
+    // blocks for the whole loop_ms instead of waking up to the backlog
+    let start = Instant::now();
+    for _ in 0..10 {
+      poll.run_once().unwrap();
+    }
+
+    assert!(start.elapsed() >= Duration::from_millis(100), "loop spun while full");
+    assert_eq!(connected(&log), 1);
+
+    // accepted once the first connection frees its slot
+    drop(first);
+    poll_until(&mut poll, || connected(&log) == 2);
+
+    let stats = poll.handler().metrics().snapshot();
+    assert_eq!((stats.accepts, stats.rejected, stats.active), (2, 0, 1));
+    assert_eq!((stats.closed, stats.hangups, stats.errors), (0, 1, 0));
+  }
+
+  #[test]
+  fn resumes_backlog_on_reconfigure() {
+    let (factory, log) = new_factory();
+    let config = MuxConfig {
+      reserve: 1,
+      max_conn: Some(1),
+      overflow: Overflow::Backlog,
+      ..Default::default()
+    };
+
+    let (mut poll, listener) = new_mux(config, factory);
+
+    let _first = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    poll_until(&mut poll, || connected(&log) == 1);
+
+    let _second = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    for _ in 0..10 {
+      poll.run_once().unwrap();
+    }
+    assert_eq!(connected(&log), 1);
+
+    // accepted once the limit is raised, with the first connection still open
+    poll.handler_mut().reconfigure(MuxConfig {
+      max_conn: Some(2),
+      overflow: Overflow::Backlog,
+      ..Default::default()
+    });
+    poll_until(&mut poll, || connected(&log) == 2);
+
+    let stats = poll.handler().metrics().snapshot();
+    assert_eq!((stats.accepts, stats.rejected, stats.active), (2, 0, 2));
+    assert_eq!((stats.closed, stats.hangups, stats.errors), (0, 0, 0));
+  }
+
+  #[test]
+  fn drops_stale_events_after_slot_reuse() {
+    let (factory, log) = new_factory();
+    let accepted = factory.accepted.clone();
+    let config = MuxConfig {
+      reserve: 1,
+      max_conn: Some(1),
+      overflow: Overflow::Backlog,
+      ..Default::default()
+    };
+
+    let (mut poll, listener) = new_mux(config, factory);
+    let srvfd = listener.as_raw_fd();
+
+    let _a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    poll_until(&mut poll, || connected(&log) == 1);
+
+    let fd = accepted.borrow()[0];
+    let stale = Action::encode(Action::Notify(0, 0, fd));
+
+    let _b = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    // a single epoll_wait batch: a's hangup, b's accept into the freed slot
+    // and another event for a collected before a was closed
+    let mux = poll.handler_mut();
+
+    mux.on_next(EpollEvent {
+      events: EPOLLRDHUP,
+      data: stale,
+    });
+
+    let start = Instant::now();
+    while accepted.borrow().len() == 1 {
+      assert!(start.elapsed() < Duration::from_secs(5), "b was not accepted");
+      mux.on_next(EpollEvent {
+        events: EPOLLIN,
+        data: Action::encode(Action::New(srvfd, 0)),
+      });
+    }
+
+    // slot 0 has been reused by b, most likely with a's file descriptor too
+    let b_fd = accepted.borrow()[1];
+    let events = log.borrow().len();
+
+    mux.on_next(EpollEvent {
+      events: EPOLLIN,
+      data: stale,
+    });
+
+    assert_eq!(log.borrow().len(), events, "stale event dispatched to b");
+
+    mux.on_next(EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::Notify(0, 1, b_fd)),
+    });
+
+    assert_eq!(log.borrow().len(), events + 1);
+    assert_eq!(log.borrow()[events], (b_fd, MuxEventKind::Io));
+  }
+
+  #[test]
+  fn accepts_in_batches() {
+    let (factory, _) = new_factory();
+    let accepted = factory.accepted.clone();
+    let config = MuxConfig {
+      max_conn: Some(4),
+      accept_batch: 3,
+      ..Default::default()
+    };
+
+    let (mut poll, listener) = new_mux(config, factory);
+    let srvfd = listener.as_raw_fd();
+
+    let _clients: Vec<TcpStream> = (0..5)
+      .map(|_| TcpStream::connect(listener.local_addr().unwrap()).unwrap())
+      .collect();
+
+    let start = Instant::now();
+    while ::prop::stats::accept_queue(srvfd).unwrap().0 < 5 {
+      assert!(start.elapsed() < Duration::from_secs(5), "connections not queued");
+    }
+
+    let new = EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::New(srvfd, 0)),
+    };
+
+    let mux = poll.handler_mut();
+
+    mux.on_next(new);
+    assert_eq!(accepted.borrow().len(), 3);
+
+    // bounded by the free slots
+    mux.on_next(new);
+    assert_eq!(accepted.borrow().len(), 4);
+
+    for &fd in accepted.borrow().iter() {
+      let flags = unsafe { ::libc_sys::fcntl(fd, ::libc_sys::F_GETFL) };
+      assert!(flags & ::libc_sys::O_NONBLOCK != 0);
+
+      let fd_flags = unsafe { ::libc_sys::fcntl(fd, ::libc_sys::F_GETFD) };
+      assert!(fd_flags & ::libc_sys::FD_CLOEXEC != 0);
+    }
+  }
+
+  #[test]
+  fn closes_unregistered_connection() {
+    let (factory, log) = new_factory();
+    let accepted = factory.accepted.clone();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let srvfd = listener.as_raw_fd();
+
+    // not an epoll instance, registering the connection fails with EINVAL
+    let mut mux = SyncMux::new(Default::default(), EpollFd::new(srvfd), factory);
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let start = Instant::now();
+    while ::prop::stats::accept_queue(srvfd).unwrap().0 < 1 {
+      assert!(start.elapsed() < Duration::from_secs(5), "connection not queued");
+    }
+
+    mux.on_next(EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::New(srvfd, 0)),
+    });
+
+    assert!(mux.handlers.is_empty());
+    assert!(accepted.borrow().is_empty());
+    assert!(log.borrow().is_empty());
+
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+
+    let stats = mux.metrics().snapshot();
+    assert_eq!((stats.accepts, stats.active), (0, 0));
+    assert_eq!(stats.accept_errors, vec!((errno::EINVAL, 1)));
+  }
+
+  #[test]
+  fn takes_resources_from_listener_factory() {
+    let (factory, _) = new_factory();
+    let admin = TestFactory {
+      resource: 1,
+      ..factory.clone()
+    };
+
+    let (mut poll, listener) = new_mux(Default::default(), factory);
+    assert_eq!(poll.handler_mut().add_factory(admin), 1);
+
+    let srvfd = listener.as_raw_fd();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let new = EpollEvent {
+      events: EPOLLIN,
+      data: Action::encode(Action::New(srvfd, 1)),
+    };
+
+    let mux = poll.handler_mut();
+
+    while mux.handlers.is_empty() {
+      mux.on_next(new);
+    }
+
+    assert_eq!(mux.resources[0], TestResource(1));
+    assert_eq!(mux.resources[1], TestResource(0));
+  }
+
+  #[test]
+  fn drains_open_connections() {
+    let (factory, log) = new_factory();
+
+    let (mut poll, listener) = new_mux(Default::default(), factory);
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    while log.borrow().is_empty() {
+      poll.run_once().unwrap();
+    }
+
+    poll.epfd.unregister(listener.as_raw_fd()).unwrap();
+    poll.handler_mut().drain();
+
+    if let EpollCmd::Shutdown = poll.run_once().unwrap() {
+      panic!("shut down with an open connection");
+    }
+
+    drop(client);
+
+    let start = Instant::now();
+    loop {
+      assert!(start.elapsed() < Duration::from_secs(5), "mux did not drain");
+      if let EpollCmd::Shutdown = poll.run_once().unwrap() {
+        break;
+      }
+    }
+  }
+
+  #[test]
+  fn delivers_expired_deadline() {
+    let log: Log = Rc::new(RefCell::new(Vec::new()));
+    let factory = TestFactory {
+      log: log.clone(),
+      accepted: Rc::new(RefCell::new(Vec::new())),
+      idle: Some(Duration::from_millis(20)),
+      resource: 0,
+    };
+
+    let (mut poll, listener) = new_mux(Default::default(), factory);
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let start = Instant::now();
+    while !log.borrow().iter().any(|&(_, kind)| kind != MuxEventKind::Io) {
+      assert!(start.elapsed() < Duration::from_secs(5), "deadline did not expire");
+      poll.run_once().unwrap();
+    }
+
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(log.borrow().last().unwrap().1, MuxEventKind::Deadline(Deadline::Idle));
+
+    // handler closed the connection on expiry
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+  }
+
+  #[test]
+  fn connects_outbound() {
+    let (factory, log) = new_factory();
+    let (mut poll, listener) = new_mux(Default::default(), factory);
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let addr = SockAddr::new_inet(InetAddr::from_std(&backend.local_addr().unwrap()));
+    let fd = poll.handler_mut().connect(&addr).unwrap();
+
+    poll_until(&mut poll, || !log.borrow().is_empty());
+    assert_eq!(log.borrow()[0], (fd, MuxEventKind::Connected));
+
+    // shares the slab with accepted connections
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    poll_until(&mut poll, || connected(&log) == 2);
+
+    let (_, peer) = backend.accept().unwrap();
+    assert!(getsockname(fd).unwrap() == SockAddr::new_inet(InetAddr::from_std(&peer)));
+  }
+
+  #[test]
+  fn reports_failed_connect() {
+    let (factory, log) = new_factory();
+    let (mut poll, _) = new_mux(Default::default(), factory);
+
+    // reserve a port nobody listens on
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let handle = poll.handle().unwrap();
+    handle.connect(SockAddr::new_inet(InetAddr::from_std(&addr))).unwrap();
+
+    poll_until(&mut poll, || !log.borrow().is_empty());
+
+    let (_, kind) = log.borrow()[0];
+    assert_eq!(kind, MuxEventKind::ConnectFailed(errno::ECONNREFUSED));
+
+    // connection was closed whatever the handler said
+    assert!(poll.handler().handlers.is_empty());
   }
 }
@@ -0,0 +1,144 @@
+use epoll::stats::add;
+use error::errno::Errno;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why a `SyncMux` closed a connection, after the handler returned `MuxCmd::Close`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+  /// On the handler's own account, e.g. on a deadline
+  Close,
+  /// On an `EPOLLHUP` or `EPOLLRDHUP` event
+  Hangup,
+  /// On an `EPOLLERR` event or a failed `connect`
+  Error,
+}
+
+/// Counters of the muxes cloned from one another, such as the I/O loops
+/// of a `Server`. Clones share the counters.
+#[derive(Debug, Clone, Default)]
+pub struct MuxMetrics {
+  muxes: Arc<Mutex<Vec<Arc<MuxCounters>>>>,
+}
+
+/// Counters of one mux. Only the thread of the mux updates them, with plain
+/// loads and stores; accept errors are rare enough to take a lock.
+#[derive(Debug, Default)]
+pub struct MuxCounters {
+  active: AtomicU64,
+  accepts: AtomicU64,
+  rejected: AtomicU64,
+  closed: AtomicU64,
+  hangups: AtomicU64,
+  errors: AtomicU64,
+  bytes_in: AtomicU64,
+  bytes_out: AtomicU64,
+  accept_errors: Mutex<Vec<(Errno, u64)>>,
+}
+
+/// Connection counters summed over muxes, since they were created.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MuxStats {
+  /// Connections open, accepted and outbound
+  pub active: u64,
+  pub accepts: u64,
+  /// Failed `accept4` calls, and accepted connections that could not be
+  /// registered, by errno
+  pub accept_errors: Vec<(Errno, u64)>,
+  /// Connections accepted and closed straight away over `MuxConfig::max_conn`
+  pub rejected: u64,
+  /// Connections closed by `CloseReason::Close`
+  pub closed: u64,
+  /// Connections closed by `CloseReason::Hangup`
+  pub hangups: u64,
+  /// Connections closed by `CloseReason::Error`
+  pub errors: u64,
+  /// Bytes the handlers reported with `MuxCounters::bytes_in`
+  pub bytes_in: u64,
+  /// Bytes the handlers reported with `MuxCounters::bytes_out`
+  pub bytes_out: u64,
+}
+
+impl MuxMetrics {
+  /// Counters of a new mux.
+  pub fn register(&self) -> Arc<MuxCounters> {
+    let counters = Arc::new(MuxCounters::default());
+    self.muxes.lock().unwrap().push(counters.clone());
+    counters
+  }
+
+  pub fn snapshot(&self) -> MuxStats {
+    let mut stats = MuxStats::default();
+
+    for mux in self.muxes.lock().unwrap().iter() {
+      stats.active += get(&mux.active);
+      stats.accepts += get(&mux.accepts);
+      stats.rejected += get(&mux.rejected);
+      stats.closed += get(&mux.closed);
+      stats.hangups += get(&mux.hangups);
+      stats.errors += get(&mux.errors);
+      stats.bytes_in += get(&mux.bytes_in);
+      stats.bytes_out += get(&mux.bytes_out);
+
+      for &(errno, n) in mux.accept_errors.lock().unwrap().iter() {
+        count(&mut stats.accept_errors, errno, n);
+      }
+    }
+
+    stats
+  }
+}
+
+impl MuxCounters {
+  /// Report `n` bytes read from a connection.
+  #[inline]
+  pub fn bytes_in(&self, n: usize) {
+    add(&self.bytes_in, n as u64);
+  }
+
+  /// Report `n` bytes written to a connection.
+  #[inline]
+  pub fn bytes_out(&self, n: usize) {
+    add(&self.bytes_out, n as u64);
+  }
+
+  #[inline]
+  pub fn active(&self, n: usize) {
+    self.active.store(n as u64, Ordering::Relaxed);
+  }
+
+  #[inline]
+  pub fn accepted(&self) {
+    add(&self.accepts, 1);
+  }
+
+  #[inline]
+  pub fn rejected(&self) {
+    add(&self.rejected, 1);
+  }
+
+  pub fn accept_failed(&self, errno: Errno) {
+    count(&mut self.accept_errors.lock().unwrap(), errno, 1);
+  }
+
+  #[inline]
+  pub fn closed(&self, reason: CloseReason) {
+    match reason {
+      CloseReason::Close => add(&self.closed, 1),
+      CloseReason::Hangup => add(&self.hangups, 1),
+      CloseReason::Error => add(&self.errors, 1),
+    }
+  }
+}
+
+#[inline]
+fn get(counter: &AtomicU64) -> u64 {
+  counter.load(Ordering::Relaxed)
+}
+
+fn count(errors: &mut Vec<(Errno, u64)>, errno: Errno, n: u64) {
+  match errors.iter_mut().find(|e| e.0 == errno) {
+    Some(e) => e.1 += n,
+    None => errors.push((errno, n)),
+  }
+}
@@ -1,10 +1,17 @@
 mod action;
+mod config;
+mod deadline;
 mod factory;
 mod event;
 #[macro_use]
 mod macros;
 mod handler;
+mod metrics;
 
-pub use self::event::{MuxCmd, MuxEvent};
-pub use self::factory::HandlerFactory;
+pub use self::action::{Action, MAX_LISTENERS};
+pub use self::config::{MuxConfig, Overflow};
+pub use self::deadline::{Deadline, Deadlines, DEADLINE_TICK_MS};
+pub use self::event::{MuxCmd, MuxEvent, MuxEventKind};
+pub use self::factory::{peer_credentials, HandlerFactory, PeerCredentials};
 pub use self::handler::SyncMux;
+pub use self::metrics::{CloseReason, MuxCounters, MuxMetrics, MuxStats};
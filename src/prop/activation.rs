@@ -0,0 +1,227 @@
+//! Socket activation: listening sockets passed by the service manager.
+//!
+//! The environment describing them is read but left as it is, since changing
+//! it races with other threads reading it. Child processes inherit the
+//! variables but ignore them, `LISTEN_PID` being another pid.
+
+use RawFd;
+use error::*;
+use libc_sys::{self, c_int, c_void, socklen_t};
+use nix::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FD_CLOEXEC, O_NONBLOCK};
+use nix::sys::socket::*;
+use nix::unistd;
+use std::env;
+use std::mem;
+use std::sync::Mutex;
+
+/// First file descriptor passed by the service manager.
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Socket passed by the service manager.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenFd {
+  pub fd: RawFd,
+  /// Name from `FileDescriptorName=` in the socket unit, "unknown" if unset
+  pub name: String,
+}
+
+/// Which of the sockets passed by the service manager a `Server` adopts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Activation {
+  /// The first one not adopted yet
+  Next,
+  /// The one with this `FileDescriptorName=`
+  Name(String),
+  /// This file descriptor, which must be a socket inherited some other way
+  Fd(RawFd),
+}
+
+lazy_static! {
+    static ref LISTEN_FDS: Mutex<Option<Vec<ListenFd>>> = Mutex::new(None);
+}
+
+/// `sd_listen_fds(3)`: the sockets passed to this process in `LISTEN_FDS`,
+/// named after `LISTEN_FDNAMES`.
+///
+/// The sockets are marked close-on-exec. The environment is left as it is
+/// (see the module documentation).
+pub fn listen_fds() -> Result<Vec<ListenFd>> {
+  let pid = env::var("LISTEN_PID").ok();
+  let fds = env::var("LISTEN_FDS").ok();
+  let names = env::var("LISTEN_FDNAMES").ok();
+
+  let (pid, fds) = match (pid, fds) {
+    (Some(pid), Some(fds)) => (pid, fds),
+    _ => return Ok(Vec::new()),
+  };
+
+  let fds = parse(&pid, &fds, names.as_deref(), unistd::getpid())?;
+
+  for lfd in &fds {
+    fcntl(lfd.fd, FcntlArg::F_SETFD(FD_CLOEXEC))?;
+  }
+
+  Ok(fds)
+}
+
+fn parse(pid: &str, fds: &str, names: Option<&str>, own_pid: i32) -> Result<Vec<ListenFd>> {
+  let pid: i32 = pid.parse().map_err(|_| format!("LISTEN_PID: invalid pid {:?}", pid))?;
+
+  if pid != own_pid {
+    debug!("LISTEN_PID: sockets were passed to pid {}", pid);
+    return Ok(Vec::new());
+  }
+
+  let n: RawFd = fds.parse().map_err(|_| format!("LISTEN_FDS: invalid count {:?}", fds))?;
+
+  let names: Vec<String> = match names {
+    Some(names) => names.split(':').map(|n| n.to_owned()).collect(),
+    None => Vec::new(),
+  };
+
+  if !names.is_empty() && names.len() != n as usize {
+    return Err(format!("LISTEN_FDNAMES: {} names for {} sockets", names.len(), n).into());
+  }
+
+  Ok((0..n)
+    .map(|i| {
+      ListenFd {
+        fd: SD_LISTEN_FDS_START + i,
+        name: names.get(i as usize).cloned().unwrap_or_else(|| "unknown".to_owned()),
+      }
+    })
+    .collect())
+}
+
+/// Take the socket selected by `activation` out of the ones passed to this process,
+/// so that each is adopted once. Returns `None` if it was not passed.
+pub fn take(activation: &Activation) -> Result<Option<RawFd>> {
+  if let Activation::Fd(fd) = *activation {
+    return Ok(Some(fd));
+  }
+
+  let mut guard = LISTEN_FDS.lock().unwrap();
+
+  if guard.is_none() {
+    *guard = Some(listen_fds()?);
+  }
+
+  let fds = guard.as_mut().unwrap();
+
+  let pos = match *activation {
+    Activation::Name(ref name) => fds.iter().position(|lfd| &lfd.name == name),
+    _ => {
+      if fds.is_empty() {
+        None
+      } else {
+        Some(0)
+      }
+    }
+  };
+
+  Ok(pos.map(|i| fds.remove(i).fd))
+}
+
+/// Check that `fd` is a socket of the given family and type, listening
+/// unless it is a datagram socket, and make it non-blocking.
+pub fn adopt(fd: RawFd, family: AddressFamily, socktype: SockType) -> Result<SockAddr> {
+  let actual = sock_type(fd)?;
+  if actual != socktype as c_int {
+    return Err(format!("adopt: fd {} has socket type {}, expected {:?}", fd, actual, socktype).into());
+  }
+
+  let sockaddr = getsockname(fd)?;
+  if sockaddr.family() != family {
+    return Err(format!("adopt: fd {} is a {:?} socket, expected {:?}",
+                       fd,
+                       sockaddr.family(),
+                       family)
+      .into());
+  }
+
+  if socktype != SockType::Datagram && !getsockopt(fd, sockopt::AcceptConn)? {
+    return Err(format!("adopt: fd {} is not listening", fd).into());
+  }
+
+  let flags = fcntl(fd, FcntlArg::F_GETFL)?;
+  fcntl(fd, FcntlArg::F_SETFL(::nix::fcntl::OFlag::from_bits_truncate(flags) | O_NONBLOCK))?;
+
+  Ok(sockaddr)
+}
+
+/// `SO_TYPE`, read as an integer since the socket may be of a type `SockType` lacks.
+fn sock_type(fd: RawFd) -> Result<c_int> {
+  let mut val: c_int = 0;
+  let mut len = mem::size_of::<c_int>() as socklen_t;
+
+  unsafe {
+    Errno::result(libc_sys::getsockopt(fd,
+                                       libc_sys::SOL_SOCKET,
+                                       libc_sys::SO_TYPE,
+                                       &mut val as *mut c_int as *mut c_void,
+                                       &mut len))?;
+  }
+
+  Ok(val)
+}
+
+#[cfg(test)]
+mod tests {
+  use nix::sys::socket::*;
+  use std::net::{TcpListener, TcpStream, UdpSocket};
+  use std::os::unix::io::AsRawFd;
+  use super::*;
+
+  #[test]
+  fn parses_listen_fds() {
+    let fds = parse("42", "2", Some("http:admin"), 42).unwrap();
+
+    assert_eq!(fds,
+               vec![ListenFd {
+                      fd: 3,
+                      name: "http".to_owned(),
+                    },
+                    ListenFd {
+                      fd: 4,
+                      name: "admin".to_owned(),
+                    }]);
+
+    assert_eq!(parse("42", "1", None, 42).unwrap()[0].name, "unknown");
+  }
+
+  #[test]
+  fn ignores_fds_for_other_pid() {
+    assert!(parse("42", "2", None, 43).unwrap().is_empty());
+  }
+
+  #[test]
+  fn rejects_invalid_listen_fds() {
+    assert!(parse("x", "1", None, 42).is_err());
+    assert!(parse("42", "-", None, 42).is_err());
+    assert!(parse("42", "2", Some("http"), 42).is_err());
+  }
+
+  #[test]
+  fn adopts_matching_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let fd = listener.as_raw_fd();
+
+    let sockaddr = adopt(fd, AddressFamily::Inet, SockType::Stream).unwrap();
+    assert_eq!(sockaddr.to_str(), listener.local_addr().unwrap().to_string());
+
+    assert!(adopt(fd, AddressFamily::Unix, SockType::Stream).is_err());
+    assert!(adopt(fd, AddressFamily::Inet, SockType::Datagram).is_err());
+  }
+
+  #[test]
+  fn rejects_socket_not_listening() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    assert!(adopt(stream.as_raw_fd(), AddressFamily::Inet, SockType::Stream).is_err());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    adopt(socket.as_raw_fd(), AddressFamily::Inet, SockType::Datagram).unwrap();
+  }
+}
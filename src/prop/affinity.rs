@@ -0,0 +1,143 @@
+use error::*;
+use libc_sys;
+use nix::Errno;
+use nix::sched::{self, CpuSet};
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+
+/// Which CPUs the I/O threads of a `Server` run on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Affinity {
+  /// Leave the threads to the scheduler
+  None,
+  /// Pin I/O loop `i` to the `i`-th CPU the process may run on, wrapping around
+  RoundRobin,
+  /// Pin I/O loop `i` to CPU `cpus[i % cpus.len()]`
+  Cpus(Vec<usize>),
+  /// Pin the I/O loops round-robin to the CPUs of the given NUMA nodes
+  /// the process may run on, e.g. the node of the network card
+  Nodes(Vec<usize>),
+}
+
+impl Default for Affinity {
+  fn default() -> Affinity {
+    Affinity::None
+  }
+}
+
+impl Affinity {
+  /// CPU of each of `io_threads` I/O loops, `None` if they are not pinned.
+  pub fn cpus(&self, io_threads: usize) -> Result<Option<Vec<usize>>> {
+    let cpus = match *self {
+      Affinity::None => return Ok(None),
+      Affinity::RoundRobin => allowed_cpus()?,
+      Affinity::Cpus(ref cpus) => {
+        let allowed = allowed_cpus()?;
+
+        if let Some(cpu) = cpus.iter().find(|cpu| !allowed.contains(cpu)) {
+          return Err(format!("affinity: the process may not run on CPU {}", cpu).into());
+        }
+
+        cpus.clone()
+      }
+      Affinity::Nodes(ref nodes) => {
+        let allowed = allowed_cpus()?;
+        let mut cpus = Vec::new();
+
+        for &node in nodes {
+          cpus.extend(node_cpus(node)?.into_iter().filter(|cpu| allowed.contains(cpu)));
+        }
+
+        cpus
+      }
+    };
+
+    if cpus.is_empty() {
+      return Err("affinity: no CPU to run the I/O threads on".into());
+    }
+
+    Ok(Some((0..io_threads).map(|i| cpus[i % cpus.len()]).collect()))
+  }
+}
+
+/// CPUs the calling thread may run on, as restricted by cpusets or
+/// the affinity it was started with.
+pub fn allowed_cpus() -> Result<Vec<usize>> {
+  let mut set: libc_sys::cpu_set_t = unsafe { mem::zeroed() };
+
+  let res = unsafe {
+    libc_sys::sched_getaffinity(0, mem::size_of::<libc_sys::cpu_set_t>(), &mut set)
+  };
+  Errno::result(res)?;
+
+  let bits = 8 * mem::size_of::<libc_sys::cpu_set_t>();
+  Ok((0..bits).filter(|&cpu| unsafe { libc_sys::CPU_ISSET(cpu, &set) }).collect())
+}
+
+/// Pin the calling thread to `cpu`.
+pub fn pin(cpu: usize) -> Result<()> {
+  let mut set = CpuSet::new();
+  set.set(cpu)?;
+  sched::sched_setaffinity(0, &set)?;
+  Ok(())
+}
+
+/// CPUs of the NUMA node `node`.
+fn node_cpus(node: usize) -> Result<Vec<usize>> {
+  let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+
+  let mut list = String::new();
+  match File::open(&path) {
+    Ok(mut file) => file.read_to_string(&mut list)?,
+    Err(e) => return Err(format!("affinity: no NUMA node {}: {}", node, e).into()),
+  };
+
+  parse_cpulist(&list)
+}
+
+/// CPUs in the kernel's list format, e.g. `0-3,8,10-11`.
+fn parse_cpulist(list: &str) -> Result<Vec<usize>> {
+  let mut cpus = Vec::new();
+
+  for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+    let mut bounds = range.splitn(2, '-');
+    let invalid = || -> Error { format!("affinity: invalid CPU list {:?}", list).into() };
+
+    let first: usize = bounds.next().unwrap().parse().map_err(|_| invalid())?;
+    let last: usize = match bounds.next() {
+      Some(last) => last.parse().map_err(|_| invalid())?,
+      None => first,
+    };
+
+    cpus.extend(first..last + 1);
+  }
+
+  Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_cpu_lists() {
+    assert_eq!(parse_cpulist("0-3,8,10-11\n").unwrap(), vec![0, 1, 2, 3, 8, 10, 11]);
+    assert_eq!(parse_cpulist("\n").unwrap(), Vec::<usize>::new());
+    assert!(parse_cpulist("0-x").is_err());
+  }
+
+  #[test]
+  fn spreads_loops_over_allowed_cpus() {
+    let allowed = allowed_cpus().unwrap();
+    assert!(!allowed.is_empty());
+
+    let cpus = Affinity::RoundRobin.cpus(allowed.len() + 1).unwrap().unwrap();
+    assert_eq!(&cpus[..allowed.len()], &allowed[..]);
+    assert_eq!(cpus[allowed.len()], allowed[0]);
+
+    assert_eq!(Affinity::None.cpus(2).unwrap(), None);
+    assert!(Affinity::Cpus(vec![libc_sys::CPU_SETSIZE as usize]).cpus(1).is_err());
+    assert!(Affinity::Cpus(Vec::new()).cpus(1).is_err());
+  }
+}
@@ -0,0 +1,397 @@
+use RawFd;
+use error::*;
+use libc_sys::{self, c_int, c_void, iovec, msghdr, size_t};
+use mux::peer_credentials;
+use nix::Errno;
+use nix::poll::{poll, PollFd, EventFlags, POLLIN};
+use nix::sys::socket::*;
+use nix::unistd;
+use prop::listener::Listener;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Most listening sockets passed in one handoff.
+pub const MAX_FDS: usize = 64;
+
+/// Longest each process waits for the other during a handoff.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the other process: `TIMEOUT`, or the watchdog
+/// `interval` if shorter, so that a stuck handoff cannot get a process killed.
+pub fn timeout(interval: Option<Duration>) -> Duration {
+  interval.map_or(TIMEOUT, |interval| interval.min(TIMEOUT))
+}
+
+const SCM_RIGHTS: c_int = 1;
+
+/// Sent by the old process along with its sockets
+const HANDOFF: u8 = b'H';
+/// Sent by the new process once it accepts on them
+const ACK: u8 = b'A';
+
+#[repr(C)]
+struct cmsghdr {
+  cmsg_len: size_t,
+  cmsg_level: c_int,
+  cmsg_type: c_int,
+}
+
+/// `SCM_RIGHTS` control message with room for `MAX_FDS` descriptors.
+#[repr(C)]
+struct Rights {
+  hdr: cmsghdr,
+  fds: [RawFd; MAX_FDS],
+}
+
+/// Socket on which a running process hands off its listening sockets to a new
+/// process taking over, e.g. a newly deployed binary (see `Takeover`).
+///
+/// The socket file is only accessible to the owner of the process.
+pub struct Handoff {
+  listener: Listener,
+  path: PathBuf,
+}
+
+impl Handoff {
+  pub fn bind<P: AsRef<Path>>(path: P) -> Result<Handoff> {
+    let sockaddr = SockAddr::Unix(UnixAddr::new(path.as_ref())?);
+
+    let mut listener = Listener::new(sockaddr, SockType::Stream, SOCK_NONBLOCK | SOCK_CLOEXEC, 0)?;
+    listener.bind(Some(0o600), None)?;
+    listener.listen(1)?;
+
+    Ok(Handoff {
+      listener: listener,
+      path: path.as_ref().to_owned(),
+    })
+  }
+
+  #[inline]
+  pub fn fd(&self) -> RawFd {
+    self.listener.fd
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// The process connecting to take over, if any.
+  pub fn accept(&self) -> Result<Option<Successor>> {
+    let fd = match syscall!(accept4(self.listener.fd, SOCK_CLOEXEC | SOCK_NONBLOCK))? {
+      Some(fd) => fd,
+      None => return Ok(None),
+    };
+
+    let mut successor = Successor {
+      fd: fd,
+      pid: 0,
+    };
+
+    successor.pid = peer_credentials(fd)?.pid;
+    Ok(Some(successor))
+  }
+}
+
+/// Connection from a process taking over, accepted on a `Handoff` socket.
+pub struct Successor {
+  fd: RawFd,
+  pid: i32,
+}
+
+impl Successor {
+  #[inline]
+  pub fn fd(&self) -> RawFd {
+    self.fd
+  }
+
+  /// Pass `fds` to the new process. Its reply is read by `taken_over` once
+  /// `fd` is readable.
+  pub fn hand_off(&self, fds: &[RawFd]) -> Result<()> {
+    info!("handoff: passing {} listening sockets to pid {}", fds.len(), self.pid);
+    send_msg(self.fd, HANDOFF, fds)
+  }
+
+  /// Whether the new process accepts on the sockets passed, after which this
+  /// process is expected to stop accepting and drain. `false` until it replies.
+  pub fn taken_over(&self) -> Result<bool> {
+    match read_msg(self.fd)? {
+      Some((Some(ACK), _)) => Ok(true),
+      Some((Some(b), _)) => Err(format!("handoff: unexpected reply {:?}", b).into()),
+      Some((None, _)) => Err(format!("handoff: pid {} exited before taking over", self.pid).into()),
+      None => Ok(false),
+    }
+  }
+}
+
+impl Drop for Successor {
+  fn drop(&mut self) {
+    let _ = unistd::close(self.fd);
+  }
+}
+
+/// Listening sockets taken over from a running process through its `Handoff` socket.
+///
+/// Sockets not taken are closed on drop.
+pub struct Takeover {
+  fd: RawFd,
+  fds: Vec<RawFd>,
+}
+
+impl Takeover {
+  /// Receive the listening sockets of the process serving a `Handoff` at `path`,
+  /// waiting up to `timeout` for them (see `timeout`). `None` if no process is.
+  pub fn connect<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Option<Takeover>> {
+    let sockaddr = SockAddr::Unix(UnixAddr::new(path.as_ref())?);
+
+    let fd = socket(AddressFamily::Unix, SockType::Stream, SOCK_CLOEXEC, 0)?;
+
+    let mut takeover = Takeover {
+      fd: fd,
+      fds: Vec::new(),
+    };
+
+    match connect(fd, &sockaddr) {
+      Ok(()) => {}
+      Err(NixError::Sys(errno::ENOENT)) |
+      Err(NixError::Sys(errno::ECONNREFUSED)) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    }
+
+    match recv_msg(fd, timeout)? {
+      (Some(HANDOFF), fds) => takeover.fds = fds,
+      (Some(b), _) => return Err(format!("handoff: unexpected message {:?}", b).into()),
+      (None, _) => return Err("handoff: connection closed by the running process".into()),
+    }
+
+    info!("handoff: received {} listening sockets", takeover.fds.len());
+
+    Ok(Some(takeover))
+  }
+
+  /// Take the socket bound to `sockaddr`, if one was received.
+  pub fn take(&mut self, sockaddr: &SockAddr) -> Result<Option<RawFd>> {
+    for i in 0..self.fds.len() {
+      if getsockname(self.fds[i])? == *sockaddr {
+        return Ok(Some(self.fds.remove(i)));
+      }
+    }
+
+    Ok(None)
+  }
+
+  /// Let the old process know that this one accepts on the sockets taken.
+  pub fn complete(self) -> Result<()> {
+    send_msg(self.fd, ACK, &[])
+  }
+}
+
+impl Drop for Takeover {
+  fn drop(&mut self) {
+    for fd in self.fds.drain(..) {
+      warn!("handoff: closing listening socket fd {} not taken", fd);
+      let _ = unistd::close(fd);
+    }
+
+    let _ = unistd::close(self.fd);
+  }
+}
+
+fn send_msg(fd: RawFd, b: u8, fds: &[RawFd]) -> Result<()> {
+  if fds.len() > MAX_FDS {
+    return Err(format!("handoff: cannot pass more than {} sockets", MAX_FDS).into());
+  }
+
+  let mut buf = [b];
+  let mut iov = iovec {
+    iov_base: buf.as_mut_ptr() as *mut c_void,
+    iov_len: 1,
+  };
+
+  let mut rights: Rights = unsafe { mem::zeroed() };
+  rights.hdr.cmsg_len = (mem::size_of::<cmsghdr>() + mem::size_of_val(fds)) as size_t;
+  rights.hdr.cmsg_level = libc_sys::SOL_SOCKET;
+  rights.hdr.cmsg_type = SCM_RIGHTS;
+  rights.fds[..fds.len()].copy_from_slice(fds);
+
+  let mut hdr: msghdr = unsafe { mem::zeroed() };
+  hdr.msg_iov = &mut iov;
+  hdr.msg_iovlen = 1;
+
+  if !fds.is_empty() {
+    // CMSG_SPACE: the data is padded to the alignment of the header
+    let align = mem::size_of::<size_t>();
+    hdr.msg_control = &mut rights as *mut Rights as *mut c_void;
+    hdr.msg_controllen = ((rights.hdr.cmsg_len as usize + align - 1) / align * align) as size_t;
+  }
+
+  loop {
+    match Errno::result(unsafe { libc_sys::sendmsg(fd, &hdr, libc_sys::MSG_NOSIGNAL) }) {
+      Ok(_) => return Ok(()),
+      Err(NixError::Sys(errno::EINTR)) => continue,
+      Err(e) => return Err(e.into()),
+    }
+  }
+}
+
+/// Wait up to `timeout` for one message; `None` if the peer closed the connection.
+fn recv_msg(fd: RawFd, timeout: Duration) -> Result<(Option<u8>, Vec<RawFd>)> {
+  let mut pfd = [PollFd::new(fd, POLLIN, EventFlags::empty())];
+  let ms = timeout.as_secs() as c_int * 1000 + timeout.subsec_nanos() as c_int / 1_000_000;
+
+  match syscall!(poll(&mut pfd, ms))? {
+    Some(0) | None => return Err(format!("handoff: no reply within {:?}", timeout).into()),
+    Some(_) => {}
+  }
+
+  match read_msg(fd)? {
+    Some(msg) => Ok(msg),
+    None => Err(format!("handoff: no reply within {:?}", timeout).into()),
+  }
+}
+
+/// One message, if any is pending; `Some((None, _))` if the peer closed the connection.
+fn read_msg(fd: RawFd) -> Result<Option<(Option<u8>, Vec<RawFd>)>> {
+  let mut buf = [0_u8];
+  let mut iov = iovec {
+    iov_base: buf.as_mut_ptr() as *mut c_void,
+    iov_len: 1,
+  };
+
+  let mut rights: Rights = unsafe { mem::zeroed() };
+
+  let mut hdr: msghdr = unsafe { mem::zeroed() };
+  hdr.msg_iov = &mut iov;
+  hdr.msg_iovlen = 1;
+  hdr.msg_control = &mut rights as *mut Rights as *mut c_void;
+  hdr.msg_controllen = mem::size_of::<Rights>() as size_t;
+
+  let flags = (MSG_CMSG_CLOEXEC | MSG_DONTWAIT).bits();
+
+  let n = loop {
+    match Errno::result(unsafe { libc_sys::recvmsg(fd, &mut hdr, flags) }) {
+      Ok(n) => break n,
+      Err(NixError::Sys(errno::EINTR)) => continue,
+      Err(NixError::Sys(errno::EAGAIN)) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    }
+  };
+
+  let mut fds = Vec::new();
+
+  if hdr.msg_controllen as usize >= mem::size_of::<cmsghdr>() &&
+     rights.hdr.cmsg_level == libc_sys::SOL_SOCKET && rights.hdr.cmsg_type == SCM_RIGHTS {
+    let len = rights.hdr.cmsg_len as usize - mem::size_of::<cmsghdr>();
+    fds.extend_from_slice(&rights.fds[..len / mem::size_of::<RawFd>()]);
+  }
+
+  if hdr.msg_flags & MSG_CTRUNC.bits() != 0 {
+    for fd in fds {
+      let _ = unistd::close(fd);
+    }
+    return Err(format!("handoff: received more than {} sockets", MAX_FDS).into());
+  }
+
+  if n == 0 {
+    return Ok(Some((None, fds)));
+  }
+
+  Ok(Some((Some(buf[0]), fds)))
+}
+
+#[cfg(test)]
+mod tests {
+  use nix::unistd;
+  use std::net::{TcpListener, TcpStream};
+  use std::os::unix::io::AsRawFd;
+  use std::thread;
+  use std::time::Duration;
+  use super::*;
+
+  #[test]
+  fn hands_off_listening_sockets() {
+    let path = ::std::env::temp_dir().join(format!("rux-handoff-{}.sock", unistd::getpid()));
+    let _ = ::std::fs::remove_file(&path);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let sockaddr = getsockname(listener.as_raw_fd()).unwrap();
+
+    let handoff = Handoff::bind(&path).unwrap();
+
+    let new = {
+      let path = path.clone();
+      thread::spawn(move || {
+        let mut takeover = Takeover::connect(&path, TIMEOUT).unwrap().unwrap();
+        let fd = takeover.take(&sockaddr).unwrap().unwrap();
+        takeover.complete().unwrap();
+        fd
+      })
+    };
+
+    let successor = loop {
+      match handoff.accept().unwrap() {
+        Some(successor) => break successor,
+        None => thread::sleep(Duration::from_millis(1)),
+      }
+    };
+
+    drop(handoff);
+    assert!(!path.exists());
+
+    successor.hand_off(&[listener.as_raw_fd()]).unwrap();
+
+    let fd = new.join().unwrap();
+    assert!(successor.taken_over().unwrap());
+    assert!(fd != listener.as_raw_fd());
+
+    // the new process accepts connections queued on the same socket
+    let _stream = TcpStream::connect(addr).unwrap();
+    drop(listener);
+    accept(fd).unwrap();
+    unistd::close(fd).unwrap();
+  }
+
+  #[test]
+  fn takes_over_nothing_without_handoff() {
+    let path = ::std::env::temp_dir().join(format!("rux-no-handoff-{}.sock", unistd::getpid()));
+    assert!(Takeover::connect(&path, TIMEOUT).unwrap().is_none());
+  }
+
+  #[test]
+  fn waits_for_successor_without_blocking() {
+    let path = ::std::env::temp_dir().join(format!("rux-handoff-wait-{}.sock", unistd::getpid()));
+    let _ = ::std::fs::remove_file(&path);
+
+    let handoff = Handoff::bind(&path).unwrap();
+
+    let new = {
+      let path = path.clone();
+      thread::spawn(move || Takeover::connect(&path, TIMEOUT).unwrap().unwrap())
+    };
+
+    let successor = loop {
+      match handoff.accept().unwrap() {
+        Some(successor) => break successor,
+        None => thread::sleep(Duration::from_millis(1)),
+      }
+    };
+
+    successor.hand_off(&[]).unwrap();
+    let takeover = new.join().unwrap();
+
+    // no reply yet
+    assert!(!successor.taken_over().unwrap());
+
+    // the new process exits without completing
+    drop(takeover);
+    assert!(successor.taken_over().is_err());
+  }
+
+  #[test]
+  fn caps_timeout_at_watchdog_interval() {
+    assert_eq!(timeout(None), TIMEOUT);
+    assert_eq!(timeout(Some(Duration::from_secs(1))), Duration::from_secs(1));
+    assert_eq!(timeout(Some(Duration::from_secs(60))), TIMEOUT);
+  }
+}
@@ -0,0 +1,296 @@
+use RawFd;
+use error::*;
+use nix::Errno;
+use nix::sys::socket::*;
+use nix::unistd;
+use prop::activation;
+use prop::options::SocketOptions;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Socket a `Server` accepts connections or receives datagrams on.
+///
+/// Closed on drop, removing the socket file of a unix listener it bound.
+pub struct Listener {
+  pub fd: RawFd,
+  /// Configured address, or the actual one of an adopted socket
+  pub sockaddr: SockAddr,
+  /// Index of the listener of the `Server` this socket belongs to
+  pub id: usize,
+  /// I/O loop that alone accepts on a `SO_REUSEPORT` socket, `None` if all do
+  pub shard: Option<usize>,
+  socktype: SockType,
+  adopted: bool,
+  bound: bool,
+  handed_off: bool,
+}
+
+impl Listener {
+  pub fn new(sockaddr: SockAddr, socktype: SockType, sockflag: SockFlag, sockproto: i32)
+             -> Result<Listener> {
+    let fd = socket(sockaddr.family(), socktype, sockflag, sockproto)?;
+
+    let listener = Listener {
+      fd: fd,
+      sockaddr: sockaddr,
+      id: 0,
+      shard: None,
+      socktype: socktype,
+      adopted: false,
+      bound: false,
+      handed_off: false,
+    };
+
+    setsockopt(fd, sockopt::ReuseAddr, &true)?;
+
+    Ok(listener)
+  }
+
+  /// Take over a socket that is already bound, and listening unless it is
+  /// a datagram socket, after checking that it has the given family and type.
+  pub fn adopt(fd: RawFd, family: AddressFamily, socktype: SockType) -> Result<Listener> {
+    let sockaddr = activation::adopt(fd, family, socktype)?;
+    info!("adopt: fd {} listening on {}", fd, sockaddr);
+
+    Ok(Listener {
+      fd: fd,
+      sockaddr: sockaddr,
+      id: 0,
+      shard: None,
+      socktype: socktype,
+      adopted: true,
+      bound: true,
+      handed_off: false,
+    })
+  }
+
+  /// Take over a socket handed off by a process this one replaces. Unlike an
+  /// adopted socket, it is then managed as if this process had opened it.
+  pub fn take_over(fd: RawFd, family: AddressFamily, socktype: SockType) -> Result<Listener> {
+    let mut listener = Listener::adopt(fd, family, socktype)?;
+    listener.adopted = false;
+    Ok(listener)
+  }
+
+  /// Leave the socket file of a unix listener to the process it was handed off to.
+  pub fn hand_off(&mut self) {
+    self.handed_off = true;
+  }
+
+  /// Set `SO_REUSEPORT` before binding, making this the socket of the I/O loop `shard`.
+  pub fn reuse_port(&mut self, shard: usize) -> Result<()> {
+    setsockopt(self.fd, sockopt::ReusePort, &true)?;
+    self.shard = Some(shard);
+    Ok(())
+  }
+
+  /// Open another `SO_REUSEPORT` socket with `options`, bound to the same address
+  /// as this one, for the I/O loop `shard`.
+  pub fn shard(&self, shard: usize, sockflag: SockFlag, sockproto: i32, options: &SocketOptions)
+               -> Result<Listener> {
+    let mut listener = Listener::new(getsockname(self.fd)?, self.socktype, sockflag, sockproto)?;
+    options.apply(listener.fd)?;
+    listener.reuse_port(shard)?;
+    listener.bind(None, None)?;
+
+    // known by the configured address, which may have left the port to the kernel
+    listener.sockaddr = self.sockaddr;
+    listener.id = self.id;
+
+    Ok(listener)
+  }
+
+  /// Whether the I/O loop `io_loop` accepts on this socket.
+  #[inline]
+  pub fn serves(&self, io_loop: usize) -> bool {
+    self.shard.map_or(true, |shard| shard == io_loop)
+  }
+
+  #[inline]
+  pub fn is_adopted(&self) -> bool {
+    self.adopted
+  }
+
+  /// Bind to the configured address, unless already bound. The socket file of
+  /// a unix listener gets `unix_mode` and `unix_owner` if given.
+  pub fn bind(&mut self, unix_mode: Option<u32>, unix_owner: Option<(u32, u32)>) -> Result<()> {
+    if self.bound {
+      return Ok(());
+    }
+
+    let path = self.unix_path();
+
+    if let Some(ref path) = path {
+      unlink_stale(&self.sockaddr, self.socktype, path)?;
+    }
+
+    syscall!(bind(self.fd, &self.sockaddr))?;
+    self.bound = true;
+    info!("bind: fd {} to {}", self.fd, self.sockaddr);
+
+    if let Some(ref path) = path {
+      if let Some(mode) = unix_mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+      }
+
+      if let Some((uid, gid)) = unix_owner {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+          .map_err(|_| format!("chown: nul byte in {}", path.display()))?;
+        unsafe {
+          Errno::result(::libc_sys::chown(cpath.as_ptr(), uid, gid))?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Start listening with the given backlog, or change the backlog
+  /// if already listening. Datagram and adopted sockets are left as they are.
+  pub fn listen(&self, backlog: usize) -> Result<()> {
+    if self.socktype == SockType::Datagram || self.adopted {
+      return Ok(());
+    }
+
+    syscall!(listen(self.fd, backlog))?;
+    info!("listen: fd {} with max connections: {}", self.fd, backlog);
+
+    Ok(())
+  }
+
+  /// Filesystem path of a unix listener.
+  fn unix_path(&self) -> Option<PathBuf> {
+    match self.sockaddr {
+      SockAddr::Unix(ref addr) => addr.path().map(|p| p.to_owned()),
+      _ => None,
+    }
+  }
+}
+
+impl Drop for Listener {
+  fn drop(&mut self) {
+    if let Err(e) = unistd::close(self.fd) {
+      report_err!(e.into());
+    }
+
+    // the socket file of an adopted socket belongs to whoever bound it
+    if self.bound && !self.adopted && !self.handed_off {
+      if let Some(path) = self.unix_path() {
+        if let Err(e) = fs::remove_file(&path) {
+          report_err!(e.into());
+        }
+      }
+    }
+  }
+}
+
+/// Address of `name` in the abstract unix socket namespace.
+pub fn unix_abstract_addr(name: &[u8]) -> Result<UnixAddr> {
+  let mut addr = UnixAddr::new_abstract(name)?;
+
+  // nix leaves the leading null byte out of the address length
+  if addr.1 + 1 > addr.0.sun_path.len() {
+    return Err(NixError::Sys(errno::ENAMETOOLONG).into());
+  }
+  addr.1 += 1;
+
+  Ok(addr)
+}
+
+/// Remove the socket file at `path` if nothing is listening on it anymore.
+fn unlink_stale(sockaddr: &SockAddr, socktype: SockType, path: &Path) -> Result<()> {
+  match fs::symlink_metadata(path) {
+    Ok(ref meta) if meta.file_type().is_socket() => {}
+    // let bind report anything else at that path
+    _ => return Ok(()),
+  }
+
+  let fd = socket(AddressFamily::Unix, socktype, SOCK_CLOEXEC, 0)?;
+  let res = connect(fd, sockaddr);
+  unistd::close(fd)?;
+
+  match res {
+    Err(NixError::Sys(errno::ECONNREFUSED)) => {
+      info!("bind: removing stale socket file {}", path.display());
+      fs::remove_file(path)?;
+      Ok(())
+    }
+    Ok(()) => Err(format!("bind: {} is in use", path.display()).into()),
+    Err(e) => Err(e.into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use nix::sys::socket::*;
+  use nix::unistd;
+  use std::os::unix::fs::MetadataExt;
+  use std::os::unix::net::UnixListener;
+  use std::path::PathBuf;
+  use super::*;
+
+  fn socket_path(name: &str) -> PathBuf {
+    let path = ::std::env::temp_dir().join(format!("rux-{}-{}.sock", name, unistd::getpid()));
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  #[test]
+  fn removes_stale_socket_file() {
+    let path = socket_path("stale");
+    drop(UnixListener::bind(&path).unwrap());
+
+    let sockaddr = SockAddr::Unix(UnixAddr::new(&path).unwrap());
+    unlink_stale(&sockaddr, SockType::Stream, &path).unwrap();
+
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn keeps_socket_file_in_use() {
+    let path = socket_path("live");
+    let _listener = UnixListener::bind(&path).unwrap();
+
+    let sockaddr = SockAddr::Unix(UnixAddr::new(&path).unwrap());
+    assert!(unlink_stale(&sockaddr, SockType::Stream, &path).is_err());
+    assert!(path.exists());
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn removes_socket_file_on_drop() {
+    let path = socket_path("drop");
+    let sockaddr = SockAddr::Unix(UnixAddr::new(&path).unwrap());
+
+    let mut listener = Listener::new(sockaddr, SockType::SeqPacket, SOCK_CLOEXEC, 0).unwrap();
+    let (uid, gid) = unsafe { (::libc_sys::getuid(), ::libc_sys::getgid()) };
+    listener.bind(Some(0o600), Some((uid, gid))).unwrap();
+    listener.listen(1).unwrap();
+
+    let metadata = fs::metadata(&path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+
+    drop(listener);
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn binds_abstract_name() {
+    let name = format!("rux-abstract-{}", unistd::getpid());
+    let sockaddr = SockAddr::Unix(unix_abstract_addr(name.as_bytes()).unwrap());
+
+    let mut listener = Listener::new(sockaddr, SockType::Stream, SOCK_CLOEXEC, 0).unwrap();
+    listener.bind(None, None).unwrap();
+    listener.listen(1).unwrap();
+
+    let fd = socket(AddressFamily::Unix, SockType::Stream, SOCK_CLOEXEC, 0).unwrap();
+    let addr = SockAddr::Unix(unix_abstract_addr(name.as_bytes()).unwrap());
+    connect(fd, &addr).unwrap();
+    unistd::close(fd).unwrap();
+  }
+}
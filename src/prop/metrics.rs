@@ -0,0 +1,496 @@
+use RawFd;
+use buf::ByteBuffer;
+use daemon::Notifier;
+use epoll::*;
+use error::*;
+use handler::Handler;
+use libc_sys::{self, c_void, MSG_NOSIGNAL};
+use mux::*;
+use nix::Errno;
+use nix::sys::socket::{recv, MSG_DONTWAIT};
+use prop::{Prop, Reload};
+use prop::affinity::Affinity;
+use prop::server::{Server, ServerConfig};
+use prop::signals::SigSet;
+use std::fmt::{Display, Write};
+use std::str;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Longest request head the metrics endpoint reads.
+const REQUEST_SIZE: usize = 4096;
+
+/// How long a client has to send its request and read the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Writes the metrics page in the Prometheus text format.
+pub type Render = Arc<dyn Fn(&mut String) + Send + Sync>;
+
+pub type MetricsMux = SyncMux<'static, MetricsHandler, MetricsFactory, ByteBuffer>;
+
+/// Prop serving `GET /metrics` on an admin listener of its own, from an event
+/// loop on a thread of its own, next to the event loops of `prop`.
+pub struct Metrics<P> {
+  prop: P,
+  admin: Server<MetricsMux>,
+  thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl<P> Metrics<P> {
+  /// Serve the page written by `render`, e.g. `ServerMetrics::write`, on the
+  /// listener of `config`. The admin listener is not handed off.
+  pub fn new<F>(prop: P, config: ServerConfig, render: F) -> Result<Metrics<P>>
+    where F: Fn(&mut String) + Send + Sync + 'static,
+  {
+    let config = config.io_threads(1).affinity(Affinity::None);
+    let factory = MetricsFactory {
+      render: Arc::new(render),
+      timeout: TIMEOUT,
+    };
+
+    Ok(Metrics {
+      prop: prop,
+      admin: Server::new(config, factory)?,
+      thread: None,
+    })
+  }
+
+  pub fn prop(&self) -> &P {
+    &self.prop
+  }
+
+  pub fn admin(&self) -> &Server<MetricsMux> {
+    &self.admin
+  }
+
+  fn stop(&mut self) {
+    let t = match self.thread.take() {
+      Some(t) => t,
+      None => return,
+    };
+
+    self.admin.shutdown();
+
+    match t.join() {
+      Ok(Ok(())) => info!("stopped metrics event loop"),
+      Ok(Err(e)) => report_err!(e),
+      Err(_) => error!("metrics event loop panicked"),
+    }
+  }
+}
+
+impl<P: Prop> Prop for Metrics<P> {
+  type EpollHandler = P::EpollHandler;
+
+  /// A failure of the metrics event loop is logged and leaves `prop` running.
+  fn setup(&mut self, mask: SigSet) -> Result<Epoll<Self::EpollHandler>> {
+    let mut epoll = self.admin.setup(mask)?;
+
+    let t = thread::Builder::new().name("rux-metrics".to_owned()).spawn(move || {
+      mask.thread_block()?;
+      info!("starting metrics event loop");
+      epoll.run()
+    })?;
+    self.thread = Some(t);
+
+    match self.prop.setup(mask) {
+      Ok(epoll) => Ok(epoll),
+      Err(e) => {
+        self.stop();
+        Err(e)
+      }
+    }
+  }
+
+  fn with_notifier(&mut self, notifier: Notifier) {
+    self.prop.with_notifier(notifier);
+  }
+
+  fn shutdown(&mut self) {
+    self.prop.shutdown();
+    self.stop();
+  }
+
+  fn listen_fds(&self) -> Vec<RawFd> {
+    self.prop.listen_fds()
+  }
+
+  fn handed_off(&mut self) {
+    self.prop.handed_off();
+  }
+
+  fn panics(&self) -> Option<Arc<::prop::threads::PanicFd>> {
+    self.prop.panics()
+  }
+}
+
+impl<P: Reload> Reload for Metrics<P> {
+  fn reload(&mut self) {
+    self.prop.reload();
+  }
+}
+
+/// Answers one HTTP request per connection, reading the request head into the
+/// connection's buffer and writing the response in its place.
+pub struct MetricsHandler {
+  render: Render,
+  timeout: Duration,
+  responding: bool,
+  closed: bool,
+}
+
+impl MetricsHandler {
+  fn receive(&mut self, fd: RawFd, buffer: &mut ByteBuffer) -> Result<()> {
+    let mut eof = false;
+
+    while buffer.is_writable() {
+      match syscall!(recv(fd, From::from(&mut *buffer), MSG_DONTWAIT))? {
+        Some(0) => {
+          eof = true;
+          break;
+        }
+        Some(n) => buffer.extend(n),
+        None => break,
+      }
+    }
+
+    let response = match head_len(buffer.slice(0)) {
+      Some(len) => self.respond(&buffer.slice(0)[..len]),
+      None if !buffer.is_writable() => reply("431 Request Header Fields Too Large", ""),
+      None => {
+        self.closed = eof;
+        return Ok(());
+      }
+    };
+
+    let readable = buffer.readable();
+    buffer.consume(readable);
+
+    if response.len() > buffer.writable() {
+      let additional = response.len() - buffer.writable();
+      buffer.reserve(additional);
+    }
+
+    buffer.write(response.as_bytes())?;
+    self.responding = true;
+
+    Ok(())
+  }
+
+  fn respond(&self, head: &[u8]) -> String {
+    let line = match str::from_utf8(head) {
+      Ok(head) => head.lines().next().unwrap_or(""),
+      Err(_) => return reply("400 Bad Request", ""),
+    };
+
+    let mut parts = line.split(' ');
+
+    match (parts.next(), parts.next().and_then(|target| target.split('?').next())) {
+      (Some("GET"), Some("/metrics")) => {
+        let mut body = String::new();
+        (self.render)(&mut body);
+        reply("200 OK", &body)
+      }
+      (Some("GET"), Some(_)) => reply("404 Not Found", ""),
+      (Some(_), Some(_)) => reply("405 Method Not Allowed", ""),
+      _ => reply("400 Bad Request", ""),
+    }
+  }
+
+  /// Send what is left of the response, and close once it is all sent.
+  fn flush(&mut self, fd: RawFd, buffer: &mut ByteBuffer) -> Result<()> {
+    while buffer.is_readable() {
+      let res = {
+        let buf: &[u8] = From::from(&*buffer);
+        // MSG_NOSIGNAL: a client gone does not raise SIGPIPE
+        syscall!(Errno::result(unsafe {
+          libc_sys::send(fd,
+                         buf.as_ptr() as *const c_void,
+                         buf.len(),
+                         MSG_DONTWAIT.bits() | MSG_NOSIGNAL)
+        }))?
+      };
+
+      match res {
+        Some(n) => buffer.consume(n as usize),
+        None => return Ok(()),
+      }
+    }
+
+    self.closed = true;
+    Ok(())
+  }
+}
+
+impl<'a> Handler<MuxEvent<'a, ByteBuffer>, MuxCmd> for MetricsHandler {
+  fn next(&mut self) -> MuxCmd {
+    if self.closed {
+      return MuxCmd::Close;
+    }
+
+    MuxCmd::Keep
+  }
+
+  fn on_next(&mut self, mut event: MuxEvent<'a, ByteBuffer>) {
+    let fd = event.fd;
+
+    if let MuxEventKind::Deadline(_) = event.kind {
+      debug!("metrics: fd {}: no request or response within {:?}", fd, self.timeout);
+      self.closed = true;
+      return;
+    }
+
+    if event.events.intersects(EPOLLERR | EPOLLHUP) {
+      self.closed = true;
+      return;
+    }
+
+    // a slow client holds a slot of the admin listener for this long at most
+    if !event.deadlines.is_armed(Deadline::Idle) {
+      event.deadlines.arm(Deadline::Idle, self.timeout);
+    }
+
+    let buffer = event.resource;
+
+    let res = if self.responding {
+      Ok(())
+    } else {
+      self.receive(fd, buffer)
+    };
+
+    let res = match res {
+      Ok(()) if self.responding => self.flush(fd, buffer),
+      res => res,
+    };
+
+    if let Err(e) = res {
+      debug!("metrics: fd {}: {}", fd, e);
+      self.closed = true;
+    }
+  }
+}
+
+impl EpollHandler for MetricsHandler {
+  fn interests() -> EpollEventKind {
+    EPOLLIN | EPOLLOUT | EPOLLRDHUP | EPOLLET
+  }
+
+  fn with_epfd(&mut self, _: EpollFd) {}
+}
+
+#[derive(Clone)]
+pub struct MetricsFactory {
+  render: Render,
+  timeout: Duration,
+}
+
+impl<'a> HandlerFactory<'a, MetricsHandler, ByteBuffer> for MetricsFactory {
+  fn new_handler(&mut self, _: EpollFd, _: RawFd) -> MetricsHandler {
+    MetricsHandler {
+      render: self.render.clone(),
+      timeout: self.timeout,
+      responding: false,
+      closed: false,
+    }
+  }
+
+  fn new_resource(&self) -> ByteBuffer {
+    ByteBuffer::with_capacity(REQUEST_SIZE)
+  }
+}
+
+/// Length of the request head in `buf`, up to the blank line ending it.
+fn head_len(buf: &[u8]) -> Option<usize> {
+  buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+fn reply(status: &str, body: &str) -> String {
+  format!("HTTP/1.1 {}\r\n\
+           Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+           Content-Length: {}\r\n\
+           Connection: close\r\n\
+           \r\n\
+           {}",
+          status,
+          body.len(),
+          body)
+}
+
+/// Counters of event loops, labelled with the index of the loop.
+pub fn write_loops(out: &mut String, loops: &[EpollStats]) {
+  if loops.is_empty() {
+    return;
+  }
+
+  let label = |i: usize| format!("loop=\"{}\"", i);
+
+  family(out, "rux_loop_waits_total", "counter",
+         "Calls to epoll_wait that returned.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.waits)));
+  family(out, "rux_loop_events_total", "counter",
+         "Events returned by epoll_wait.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.events)));
+  family(out, "rux_loop_wait_seconds_total", "counter",
+         "Time spent blocked in epoll_wait.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.wait_time.as_secs_f64())));
+  family(out, "rux_loop_handle_seconds_total", "counter",
+         "Time spent handling events.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.handle_time.as_secs_f64())));
+  family(out, "rux_loop_max_batch", "gauge",
+         "Most events returned by a single epoll_wait.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.max_batch)));
+  family(out, "rux_loop_full_batches_total", "counter",
+         "Calls to epoll_wait that filled the event buffer.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.full_batches)));
+  family(out, "rux_loop_buffer_capacity", "gauge",
+         "Capacity of the event buffer.",
+         loops.iter().enumerate().map(|(i, l)| (label(i), l.buffer_capacity)));
+}
+
+/// Connection counters of muxes.
+pub fn write_connections(out: &mut String, stats: &MuxStats) {
+  let total = |value: u64| Some((String::new(), value));
+
+  family(out, "rux_connections_active", "gauge",
+         "Open connections.",
+         total(stats.active));
+  family(out, "rux_accepts_total", "counter",
+         "Accepted connections.",
+         total(stats.accepts));
+  family(out, "rux_accept_errors_total", "counter",
+         "Failed accepts by errno.",
+         stats.accept_errors.iter().map(|&(errno, n)| (format!("errno=\"{:?}\"", errno), n)));
+  family(out, "rux_rejected_total", "counter",
+         "Connections closed straight away over the connection limit.",
+         total(stats.rejected));
+  family(out, "rux_closes_total", "counter",
+         "Closed connections by reason.",
+         vec![("reason=\"close\"".to_owned(), stats.closed),
+              ("reason=\"hangup\"".to_owned(), stats.hangups),
+              ("reason=\"error\"".to_owned(), stats.errors)]);
+  family(out, "rux_received_bytes_total", "counter",
+         "Bytes received, as reported by the handlers.",
+         total(stats.bytes_in));
+  family(out, "rux_sent_bytes_total", "counter",
+         "Bytes sent, as reported by the handlers.",
+         total(stats.bytes_out));
+}
+
+/// A metric family, one sample per set of labels.
+fn family<I, V>(out: &mut String, name: &str, kind: &str, help: &str, samples: I)
+  where I: IntoIterator<Item = (String, V)>,
+        V: Display,
+{
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+  for (labels, value) in samples {
+    if labels.is_empty() {
+      let _ = writeln!(out, "{} {}", name, value);
+    } else {
+      let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use errno::Errno;
+  use nix::sys::socket::*;
+  use prop::Prop;
+  use std::io::{Read, Write};
+  use std::net::{self, TcpStream};
+  use std::thread;
+  use super::*;
+
+  fn get(addr: net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+  }
+
+  #[test]
+  fn formats_connection_counters() {
+    let stats = MuxStats {
+      accepts: 3,
+      accept_errors: vec![(Errno::EMFILE, 2)],
+      hangups: 1,
+      ..Default::default()
+    };
+
+    let mut out = String::new();
+    write_connections(&mut out, &stats);
+
+    assert!(out.contains("# TYPE rux_accepts_total counter\nrux_accepts_total 3\n"));
+    assert!(out.contains("rux_accept_errors_total{errno=\"EMFILE\"} 2\n"));
+    assert!(out.contains("rux_closes_total{reason=\"hangup\"} 1\n"));
+  }
+
+  #[test]
+  fn serves_metrics_of_the_prop() {
+    let config = ServerConfig::tcp("127.0.0.1:0")
+      .unwrap()
+      .epoll_config(EpollConfig::default().stats(true));
+    let factory = MetricsFactory {
+      render: Arc::new(|_: &mut String| {}),
+      timeout: TIMEOUT,
+    };
+    let server = Server::new(config, factory).unwrap();
+
+    let metrics = server.metrics();
+    let admin = ServerConfig::tcp("127.0.0.1:0").unwrap();
+    let mut prop = Metrics::new(server, admin, move |out| metrics.write(out)).unwrap();
+
+    let mut epoll = prop.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    let addr = match getsockname(prop.admin().listen_fds()[0]).unwrap() {
+      SockAddr::Inet(addr) => addr.to_std(),
+      _ => unreachable!(),
+    };
+
+    let response = get(addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("\nrux_loop_waits_total{loop=\"0\"} "));
+    assert!(response.contains("\nrux_connections_active 0\n"));
+
+    assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    prop.shutdown();
+    main.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn closes_slow_clients() {
+    let config = ServerConfig::tcp("127.0.0.1:0").unwrap().io_threads(1);
+    let factory = MetricsFactory {
+      render: Arc::new(|_: &mut String| {}),
+      timeout: Duration::from_millis(50),
+    };
+    let mut server = Server::new(config, factory).unwrap();
+
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    let addr = match getsockname(server.listen_fds()[0]).unwrap() {
+      SockAddr::Inet(addr) => addr.to_std(),
+      _ => unreachable!(),
+    };
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    write!(stream, "GET /metrics HTTP/1.1\r\n").unwrap();
+
+    // closed without a response once the deadline expires
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.is_empty());
+
+    server.shutdown();
+    main.join().unwrap().unwrap();
+  }
+}
@@ -1,16 +1,52 @@
+use RawFd;
+use daemon::Notifier;
 use epoll::{EpollEvent, EpollCmd, Epoll};
 use error::Result;
 use handler::Handler;
 
+pub mod activation;
+pub mod affinity;
+pub mod handoff;
+pub mod listener;
+pub mod metrics;
+pub mod options;
+pub mod reuseport;
 pub mod server;
 pub mod signals;
+pub mod stats;
+pub mod threads;
 
 use self::signals::SigSet;
+use self::threads::PanicFd;
+use std::sync::Arc;
 
 pub trait Prop {
   type EpollHandler: Handler<EpollEvent, EpollCmd>;
 
   fn setup(&mut self, mask: SigSet) -> Result<Epoll<Self::EpollHandler>>;
+
+  /// Called before `setup` when the daemon notifies a service manager,
+  /// with a clone of its notifier to post e.g. `Notifier::status` updates.
+  fn with_notifier(&mut self, _: Notifier) {}
+
+  /// Stop the event loops started by `setup` and wait for the ones running on
+  /// threads owned by the prop. The loop returned by `setup` must be stopped too
+  /// but is joined by the caller.
+  fn shutdown(&mut self) {}
+
+  /// Listening sockets to pass on to a process taking over through a `handoff::Handoff`.
+  fn listen_fds(&self) -> Vec<RawFd> {
+    Vec::new()
+  }
+
+  /// A process took over the sockets from `listen_fds`; `shutdown` follows.
+  fn handed_off(&mut self) {}
+
+  /// Signalled when a thread running one of the prop's event loops panics
+  /// or fails, upon which the daemon shuts down.
+  fn panics(&self) -> Option<Arc<PanicFd>> {
+    None
+  }
 }
 
 pub trait Reload {
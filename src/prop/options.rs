@@ -0,0 +1,208 @@
+use RawFd;
+use error::*;
+use libc_sys::{self, c_int, c_void, socklen_t};
+use nix::Errno;
+use std::mem;
+use std::time::Duration;
+
+/// Options set on the listening sockets a `Server` opens, before they are bound.
+///
+/// Unless noted otherwise, accepted sockets inherit them from the listening socket.
+/// `None` leaves the system default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SocketOptions {
+  /// `TCP_NODELAY`: send segments as soon as possible instead of coalescing them
+  pub nodelay: Option<bool>,
+  /// `SO_KEEPALIVE`, with the given probe settings
+  pub keepalive: Option<Keepalive>,
+  /// `TCP_DEFER_ACCEPT`: only accept connections once data arrived on them,
+  /// or the given time passed. Listening socket only
+  pub defer_accept: Option<Duration>,
+  /// `TCP_FASTOPEN`: length of the queue of pending TCP Fast Open requests.
+  /// Listening socket only
+  pub fastopen: Option<u32>,
+  /// `SO_RCVBUF`, in bytes; the kernel doubles it
+  pub recv_buffer: Option<usize>,
+  /// `SO_SNDBUF`, in bytes; the kernel doubles it
+  pub send_buffer: Option<usize>,
+  /// `TCP_USER_TIMEOUT`: how long sent data may remain unacknowledged
+  /// before the connection is dropped
+  pub user_timeout: Option<Duration>,
+  /// `IPV6_V6ONLY`: whether an IPv6 socket refuses IPv4 connections
+  pub v6only: Option<bool>,
+  /// `SO_LINGER`: how long `close` waits for unsent data, resetting the
+  /// connection when zero
+  pub linger: Option<Duration>,
+}
+
+/// TCP keepalive probing; `None` leaves the system default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Keepalive {
+  /// `TCP_KEEPIDLE`: idle time before the first probe
+  pub idle: Option<Duration>,
+  /// `TCP_KEEPINTVL`: time between probes
+  pub interval: Option<Duration>,
+  /// `TCP_KEEPCNT`: unanswered probes before the connection is dropped
+  pub count: Option<u32>,
+}
+
+impl SocketOptions {
+  /// Set the options on `fd`, which must not be bound yet for `v6only` to apply.
+  pub fn apply(&self, fd: RawFd) -> Result<()> {
+    let tcp = libc_sys::IPPROTO_TCP;
+    let sol = libc_sys::SOL_SOCKET;
+
+    if let Some(nodelay) = self.nodelay {
+      setsockopt(fd, tcp, libc_sys::TCP_NODELAY, "TCP_NODELAY", &(nodelay as c_int))?;
+    }
+
+    if let Some(keepalive) = self.keepalive {
+      setsockopt(fd, sol, libc_sys::SO_KEEPALIVE, "SO_KEEPALIVE", &(1 as c_int))?;
+
+      if let Some(idle) = keepalive.idle {
+        setsockopt(fd, tcp, libc_sys::TCP_KEEPIDLE, "TCP_KEEPIDLE", &secs(idle))?;
+      }
+
+      if let Some(interval) = keepalive.interval {
+        setsockopt(fd, tcp, libc_sys::TCP_KEEPINTVL, "TCP_KEEPINTVL", &secs(interval))?;
+      }
+
+      if let Some(count) = keepalive.count {
+        setsockopt(fd, tcp, libc_sys::TCP_KEEPCNT, "TCP_KEEPCNT", &(count as c_int))?;
+      }
+    }
+
+    if let Some(timeout) = self.defer_accept {
+      setsockopt(fd, tcp, libc_sys::TCP_DEFER_ACCEPT, "TCP_DEFER_ACCEPT", &secs(timeout))?;
+    }
+
+    if let Some(qlen) = self.fastopen {
+      setsockopt(fd, tcp, libc_sys::TCP_FASTOPEN, "TCP_FASTOPEN", &(qlen as c_int))?;
+    }
+
+    if let Some(size) = self.recv_buffer {
+      setsockopt(fd, sol, libc_sys::SO_RCVBUF, "SO_RCVBUF", &(size as c_int))?;
+    }
+
+    if let Some(size) = self.send_buffer {
+      setsockopt(fd, sol, libc_sys::SO_SNDBUF, "SO_SNDBUF", &(size as c_int))?;
+    }
+
+    if let Some(timeout) = self.user_timeout {
+      let ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+      setsockopt(fd, tcp, libc_sys::TCP_USER_TIMEOUT, "TCP_USER_TIMEOUT", &ms)?;
+    }
+
+    if let Some(v6only) = self.v6only {
+      setsockopt(fd,
+                 libc_sys::IPPROTO_IPV6,
+                 libc_sys::IPV6_V6ONLY,
+                 "IPV6_V6ONLY",
+                 &(v6only as c_int))?;
+    }
+
+    if let Some(timeout) = self.linger {
+      let linger = libc_sys::linger {
+        l_onoff: 1,
+        l_linger: secs(timeout),
+      };
+      setsockopt(fd, sol, libc_sys::SO_LINGER, "SO_LINGER", &linger)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Whole seconds, rounded up so that a short timeout is not taken as none.
+fn secs(duration: Duration) -> c_int {
+  let secs = duration.as_secs() + (duration.subsec_nanos() > 0) as u64;
+  secs.min(c_int::MAX as u64) as c_int
+}
+
+fn setsockopt<T>(fd: RawFd, level: c_int, opt: c_int, name: &str, val: &T) -> Result<()> {
+  let res = unsafe {
+    libc_sys::setsockopt(fd,
+                         level,
+                         opt,
+                         val as *const T as *const c_void,
+                         mem::size_of::<T>() as socklen_t)
+  };
+
+  Errno::result(res).chain_err(|| format!("could not set {} on fd {}", name, fd))?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use libc_sys::{self, c_int, c_void, socklen_t};
+  use nix::sys::socket::*;
+  use nix::unistd;
+  use std::mem;
+  use std::net::TcpStream;
+  use std::time::Duration;
+  use super::*;
+
+  fn getsockopt(fd: RawFd, level: c_int, opt: c_int) -> c_int {
+    let mut val: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let res = unsafe {
+      libc_sys::getsockopt(fd, level, opt, &mut val as *mut c_int as *mut c_void, &mut len)
+    };
+    Errno::result(res).unwrap();
+    val
+  }
+
+  #[test]
+  fn accepted_sockets_inherit_options() {
+    let options = SocketOptions {
+      nodelay: Some(true),
+      keepalive: Some(Keepalive {
+        idle: Some(Duration::from_millis(1500)),
+        interval: Some(Duration::from_secs(5)),
+        count: Some(3),
+      }),
+      defer_accept: Some(Duration::from_secs(1)),
+      user_timeout: Some(Duration::from_secs(30)),
+      ..Default::default()
+    };
+
+    let fd = socket(AddressFamily::Inet, SockType::Stream, SOCK_CLOEXEC, 0).unwrap();
+    options.apply(fd).unwrap();
+
+    let sockaddr = SockAddr::Inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()));
+    bind(fd, &sockaddr).unwrap();
+    listen(fd, 1).unwrap();
+
+    let addr = match getsockname(fd).unwrap() {
+      SockAddr::Inet(addr) => addr.to_std(),
+      _ => unreachable!(),
+    };
+
+    // deferred until data arrives
+    let mut stream = TcpStream::connect(addr).unwrap();
+    ::std::io::Write::write_all(&mut stream, b"x").unwrap();
+    let clifd = accept(fd).unwrap();
+
+    let tcp = libc_sys::IPPROTO_TCP;
+    assert_eq!(getsockopt(clifd, tcp, libc_sys::TCP_NODELAY), 1);
+    assert_eq!(getsockopt(clifd, libc_sys::SOL_SOCKET, libc_sys::SO_KEEPALIVE), 1);
+    assert_eq!(getsockopt(clifd, tcp, libc_sys::TCP_KEEPIDLE), 2);
+    assert_eq!(getsockopt(clifd, tcp, libc_sys::TCP_KEEPINTVL), 5);
+    assert_eq!(getsockopt(clifd, tcp, libc_sys::TCP_KEEPCNT), 3);
+    assert_eq!(getsockopt(clifd, tcp, libc_sys::TCP_USER_TIMEOUT), 30000);
+
+    unistd::close(clifd).unwrap();
+    unistd::close(fd).unwrap();
+  }
+
+  #[test]
+  fn reports_unsupported_options() {
+    let fd = socket(AddressFamily::Unix, SockType::Stream, SOCK_CLOEXEC, 0).unwrap();
+
+    let options = SocketOptions { nodelay: Some(true), ..Default::default() };
+    assert!(options.apply(fd).is_err());
+
+    unistd::close(fd).unwrap();
+  }
+}
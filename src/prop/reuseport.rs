@@ -0,0 +1,165 @@
+use RawFd;
+use error::*;
+use libc_sys::{self, c_int, c_void, socklen_t};
+use nix::Errno;
+use std::mem;
+
+const SO_INCOMING_CPU: c_int = 49;
+const SO_ATTACH_REUSEPORT_CBPF: c_int = 51;
+
+/// `BPF_LD | BPF_W | BPF_ABS`
+const BPF_LD_W_ABS: u16 = 0x20;
+/// `BPF_ALU | BPF_MOD | BPF_K`
+const BPF_ALU_MOD_K: u16 = 0x94;
+/// `BPF_JMP | BPF_JEQ | BPF_K`
+const BPF_JEQ_K: u16 = 0x15;
+/// `BPF_RET | BPF_K`
+const BPF_RET_K: u16 = 0x06;
+/// `BPF_RET | BPF_A`
+const BPF_RET_A: u16 = 0x16;
+/// `SKF_AD_OFF + SKF_AD_CPU`: the CPU processing the packet
+const SKF_AD_CPU: u32 = (-0x1000_i32 + 36) as u32;
+
+/// How accepted connections are spread over the I/O threads of a `Server`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcceptMode {
+  /// One listening socket, registered with every thread's epoll with `EPOLLEXCLUSIVE`
+  Shared,
+  /// A `SO_REUSEPORT` listening socket per thread, balanced by the kernel.
+  /// Unix and adopted sockets are shared as in `Shared` mode.
+  ReusePort(Steering),
+}
+
+impl Default for AcceptMode {
+  fn default() -> AcceptMode {
+    AcceptMode::Shared
+  }
+}
+
+/// Which socket of a `SO_REUSEPORT` group the kernel picks for a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Steering {
+  /// By hash of the connection's addresses and ports
+  Hash,
+  /// Prefer the socket of the thread pinned to the CPU handling the connection,
+  /// with `SO_INCOMING_CPU`
+  IncomingCpu,
+  /// The socket of the first thread pinned to the CPU handling the connection,
+  /// or of thread `cpu % threads` if none is, with a classic BPF program
+  Cbpf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+struct sock_filter {
+  code: u16,
+  jt: u8,
+  jf: u8,
+  k: u32,
+}
+
+#[repr(C)]
+struct sock_fprog {
+  len: u16,
+  filter: *const sock_filter,
+}
+
+fn setsockopt<T>(fd: RawFd, opt: c_int, val: &T) -> Result<()> {
+  unsafe {
+    Errno::result(libc_sys::setsockopt(fd,
+                                       libc_sys::SOL_SOCKET,
+                                       opt,
+                                       val as *const T as *const c_void,
+                                       mem::size_of::<T>() as socklen_t))?;
+  }
+
+  Ok(())
+}
+
+/// Mark `fd` as the socket for connections handled by `cpu`.
+pub fn set_incoming_cpu(fd: RawFd, cpu: usize) -> Result<()> {
+  setsockopt(fd, SO_INCOMING_CPU, &(cpu as c_int))
+}
+
+/// Steer each connection to the first of the `sockets` of the `SO_REUSEPORT`
+/// group of `fd`, in the order they were bound, whose thread is pinned to the
+/// CPU handling it (socket `i` to `cpus[i]`), else to socket `cpu % sockets`.
+pub fn attach_cpu_program(fd: RawFd, sockets: usize, cpus: Option<&[usize]>) -> Result<()> {
+  let filter = cpu_program(sockets, cpus);
+
+  let prog = sock_fprog {
+    len: filter.len() as u16,
+    filter: filter.as_ptr(),
+  };
+
+  setsockopt(fd, SO_ATTACH_REUSEPORT_CBPF, &prog)
+}
+
+fn cpu_program(sockets: usize, cpus: Option<&[usize]>) -> Vec<sock_filter> {
+  let insn = |code, jf, k| {
+    sock_filter {
+      code: code,
+      jt: 0,
+      jf: jf,
+      k: k,
+    }
+  };
+
+  let mut filter = vec![insn(BPF_LD_W_ABS, 0, SKF_AD_CPU)];
+
+  let cpus = cpus.unwrap_or(&[]);
+  for (i, &cpu) in cpus.iter().enumerate().take(sockets) {
+    if cpus[..i].contains(&cpu) {
+      continue;
+    }
+
+    // return `i` if the CPU is `cpu`, else skip to the next comparison
+    filter.push(insn(BPF_JEQ_K, 1, cpu as u32));
+    filter.push(insn(BPF_RET_K, 0, i as u32));
+  }
+
+  filter.push(insn(BPF_ALU_MOD_K, 0, sockets as u32));
+  filter.push(insn(BPF_RET_A, 0, 0));
+
+  filter
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Socket the program picks for a connection handled by `cpu`.
+  fn run(filter: &[sock_filter], cpu: u32) -> u32 {
+    let mut a = 0;
+    let mut pc = 0;
+
+    loop {
+      let insn = filter[pc];
+      pc += 1;
+
+      match insn.code {
+        BPF_LD_W_ABS => a = cpu,
+        BPF_JEQ_K => pc += if a == insn.k { insn.jt } else { insn.jf } as usize,
+        BPF_ALU_MOD_K => a %= insn.k,
+        BPF_RET_K => return insn.k,
+        BPF_RET_A => return a,
+        code => panic!("unexpected instruction {:#x}", code),
+      }
+    }
+  }
+
+  #[test]
+  fn steers_to_threads_pinned_to_the_cpu() {
+    let filter = cpu_program(3, Some(&[4, 2, 4]));
+    assert_eq!(run(&filter, 4), 0);
+    assert_eq!(run(&filter, 2), 1);
+    assert_eq!(run(&filter, 5), 2);
+  }
+
+  #[test]
+  fn steers_unpinned_threads_by_cpu() {
+    let filter = cpu_program(2, None);
+    assert_eq!(run(&filter, 3), 1);
+    assert_eq!(run(&filter, 4), 0);
+  }
+}
@@ -1,53 +1,100 @@
 use ::RawFd;
+use ::Reset;
+use daemon::Notifier;
 use epoll::*;
 use error::*;
 use prop::Reload;
+use prop::activation::{self, Activation};
+use prop::affinity::{self, Affinity};
+use prop::handoff::{self, Takeover};
+use prop::listener::{unix_abstract_addr, Listener};
+use prop::metrics;
+use prop::options::SocketOptions;
+use prop::reuseport::{self, AcceptMode, Steering};
 use prop::signals::*;
+use prop::stats::{self, ListenerStats, ServerStats};
+use prop::threads::PanicFd;
 use handler::Handler;
 use mux::*;
-use nix::sched;
 use nix::sys::socket::*;
 use prop::Prop;
 use std::net;
 use std::net::ToSocketAddrs;
-use std::thread;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 pub struct Server<H> {
-  srvfd: RawFd,
+  listeners: Vec<Listener>,
   epfd: EpollFd,
-  sockaddr: SockAddr,
-  max_conn: usize,
   handler: H,
-  io_threads: usize,
-  epoll_config: EpollConfig,
+  /// One per listener, the first one given to `new`
+  configs: Vec<ServerConfig>,
+  source: Option<Box<dyn FnMut() -> Result<Vec<ServerConfig>> + Send>>,
+  init: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+  takeover: Option<Takeover>,
+  /// CPU each I/O loop is pinned to, if they are
+  cpus: Option<Vec<usize>>,
+  epfds: Vec<EpollFd>,
+  handles: Vec<EpollHandle<H>>,
+  /// Applies `ServerConfig::mux_config` to a handler on reload
+  reconfigure: Option<fn(&mut H, MuxConfig)>,
+  metrics: ServerMetrics,
+  threads: Vec<JoinHandle<()>>,
+  panics: Arc<PanicFd>,
 }
 
+#[derive(Clone)]
 pub struct ServerConfig {
-  max_conn: usize,
+  backlog: usize,
   io_threads: usize,
   socktype: SockType,
   sockaddr: SockAddr,
+  sockaddrs: Vec<SockAddr>,
   sockflag: SockFlag,
   sockproto: i32,
   family: AddressFamily,
+  socket_options: SocketOptions,
   epoll_config: EpollConfig,
+  mux_config: MuxConfig,
+  drain_timeout: Duration,
+  unix_mode: Option<u32>,
+  unix_owner: Option<(u32, u32)>,
+  activation: Option<Activation>,
+  handoff: Option<PathBuf>,
+  accept_mode: AcceptMode,
+  affinity: Affinity,
 }
 
-// TODO: provide optional socket based activation
-// http://man7.org/linux/man-pages/man7/daemon.7.html
-// http://man7.org/linux/man-pages/man3/sd_listen_fds.3.html
-// http://man7.org/linux/man-pages/man5/systemd.socket.5.html
 impl ServerConfig {
   pub fn tcp<A: ToSocketAddrs>(addr: A) -> Result<ServerConfig> {
     let (sockaddr, family) = Self::inet(addr)?;
     ServerConfig::new(sockaddr, SockType::Stream, family, SOCK_NONBLOCK, 6)
   }
 
+  /// Datagram socket, to be served by `datagram::SyncDatagram` handlers.
   pub fn udp<A: ToSocketAddrs>(addr: A) -> Result<ServerConfig> {
     let (sockaddr, family) = Self::inet(addr)?;
     ServerConfig::new(sockaddr, SockType::Datagram, family, SOCK_NONBLOCK, 17)
   }
 
+  /// Unix socket bound to a filesystem path, of type `SockType::Stream`
+  /// or `SockType::SeqPacket`.
+  ///
+  /// A stale socket file left at `path` is removed on setup, and the socket
+  /// file is removed on shutdown.
+  pub fn unix<P: AsRef<Path>>(path: P, socktype: SockType) -> Result<ServerConfig> {
+    let sockaddr = SockAddr::Unix(UnixAddr::new(path.as_ref())?);
+    ServerConfig::new(sockaddr, socktype, AddressFamily::Unix, SOCK_NONBLOCK, 0)
+  }
+
+  /// Unix socket bound to `name` in the abstract namespace.
+  pub fn unix_abstract(name: &[u8], socktype: SockType) -> Result<ServerConfig> {
+    let sockaddr = SockAddr::Unix(unix_abstract_addr(name)?);
+    ServerConfig::new(sockaddr, socktype, AddressFamily::Unix, SOCK_NONBLOCK, 0)
+  }
+
   pub fn new(sockaddr: SockAddr, socktype: SockType, family: AddressFamily, sockflag: SockFlag,
              sockproto: i32)
              -> Result<ServerConfig> {
@@ -58,33 +105,239 @@ impl ServerConfig {
 
     Ok(ServerConfig {
       sockaddr: sockaddr,
+      sockaddrs: Vec::new(),
       socktype: socktype,
       sockflag: sockflag,
       sockproto: sockproto,
       family: family,
-      max_conn: max_conn,
+      socket_options: Default::default(),
+      backlog: 4096,
       io_threads: io_threads,
       epoll_config: Default::default(),
+      mux_config: MuxConfig { max_conn: Some(max_conn), ..Default::default() },
+      drain_timeout: Duration::from_secs(5),
+      unix_mode: None,
+      unix_owner: None,
+      activation: None,
+      handoff: None,
+      accept_mode: AcceptMode::Shared,
+      affinity: Affinity::None,
     })
   }
 
+  /// Maximum number of concurrent connections per I/O thread, independent
+  /// of the `backlog` of connections waiting to be accepted.
   pub fn max_conn(self, max_conn: usize) -> ServerConfig {
-    ServerConfig { max_conn: max_conn, ..self }
+    let mux_config = MuxConfig { max_conn: Some(max_conn), ..self.mux_config };
+    ServerConfig { mux_config: mux_config, ..self }
+  }
+
+  /// Length of the accept queue of each listening socket, capped by the
+  /// `net.core.somaxconn` sysctl. Defaults to 4096.
+  pub fn backlog(self, backlog: usize) -> ServerConfig {
+    ServerConfig { backlog: backlog, ..self }
+  }
+
+  /// Listen on `sockaddr` as well, with the same socket type.
+  pub fn listen_on(mut self, sockaddr: SockAddr) -> ServerConfig {
+    self.sockaddrs.push(sockaddr);
+    self
   }
 
   pub fn sockflag(self, sockflag: SockFlag) -> ServerConfig {
     ServerConfig { sockflag: sockflag, ..self }
   }
 
+  /// Options of the listening sockets, mostly inherited by accepted connections.
+  /// Sockets adopted or taken over are left as they are, as are the ones
+  /// already open on reload.
+  pub fn socket_options(self, socket_options: SocketOptions) -> ServerConfig {
+    ServerConfig { socket_options: socket_options, ..self }
+  }
+
   pub fn io_threads(self, io_threads: usize) -> ServerConfig {
     assert!(io_threads > 0, "I/O threads must be greater than 0");
     ServerConfig { io_threads: io_threads, ..self }
   }
 
+  /// Whether the I/O threads share each listening socket or each have their own.
+  pub fn accept_mode(self, accept_mode: AcceptMode) -> ServerConfig {
+    ServerConfig { accept_mode: accept_mode, ..self }
+  }
+
+  /// Which CPUs the I/O threads are pinned to, set up once on `setup`.
+  /// Defaults to `Affinity::None`.
+  pub fn affinity(self, affinity: Affinity) -> ServerConfig {
+    ServerConfig { affinity: affinity, ..self }
+  }
+
   pub fn epoll_config(self, epoll_config: EpollConfig) -> ServerConfig {
     ServerConfig { epoll_config: epoll_config, ..self }
   }
 
+  /// Whether the I/O loops wait on epoll or io_uring, set up once on `setup`.
+  ///
+  /// io_uring poll requests ignore `EPOLLEXCLUSIVE`: a connection on a socket
+  /// shared by the I/O threads, as in `AcceptMode::Shared` or for unix sockets,
+  /// wakes all of them for one to accept it. `AcceptMode::ReusePort` avoids it.
+  pub fn backend(mut self, backend: Backend) -> ServerConfig {
+    self.epoll_config.backend = backend;
+    self
+  }
+
+  /// Connection slot reservation, ceiling and overflow policy of each I/O thread's `SyncMux`.
+  pub fn mux_config(self, mux_config: MuxConfig) -> ServerConfig {
+    ServerConfig { mux_config: mux_config, ..self }
+  }
+
+  /// How long open connections are given to finish on shutdown.
+  pub fn drain_timeout(self, drain_timeout: Duration) -> ServerConfig {
+    ServerConfig { drain_timeout: drain_timeout, ..self }
+  }
+
+  /// Permission bits of the socket file of a `unix` listener.
+  pub fn unix_mode(self, mode: u32) -> ServerConfig {
+    ServerConfig { unix_mode: Some(mode), ..self }
+  }
+
+  /// Owner and group of the socket file of a `unix` listener.
+  pub fn unix_owner(self, uid: u32, gid: u32) -> ServerConfig {
+    ServerConfig { unix_owner: Some((uid, gid)), ..self }
+  }
+
+  /// Adopt a listening socket passed by the service manager
+  /// (see `sd_listen_fds(3)`) instead of opening one, or the given inherited
+  /// socket with `Activation::Fd`.
+  ///
+  /// The socket must match the configured family and type. If the process was
+  /// not socket activated, the server opens its socket as usual.
+  pub fn activation(self, activation: Activation) -> ServerConfig {
+    ServerConfig { activation: Some(activation), ..self }
+  }
+
+  /// Take over the listening sockets of a running process that serves a
+  /// `handoff::Handoff` at `path` (see `DaemonBuilder::with_handoff`), which then
+  /// stops accepting and drains once this server is set up.
+  ///
+  /// Sockets are matched by address; the others are opened as usual, as are
+  /// all of them if no process serves `path`.
+  pub fn handoff<P: AsRef<Path>>(self, path: P) -> ServerConfig {
+    ServerConfig { handoff: Some(path.as_ref().to_owned()), ..self }
+  }
+
+  /// Open the listening sockets of the listener `id`: those handed off by
+  /// `takeover` or passed by the service manager, else new ones.
+  fn listeners(&self, id: usize, takeover: &mut Option<Takeover>) -> Result<Vec<Listener>> {
+    let mut listeners = Vec::with_capacity(1 + self.sockaddrs.len());
+
+    for (i, sockaddr) in Some(&self.sockaddr).into_iter().chain(&self.sockaddrs).enumerate() {
+      let taken = match *takeover {
+        Some(ref mut takeover) => takeover.take(sockaddr)?,
+        None => None,
+      };
+
+      if let Some(fd) = taken {
+        let mut listener = Listener::take_over(fd, sockaddr.family(), self.socktype)?;
+        listener.id = id;
+
+        // a socket handed off in shared mode cannot be sharded
+        if self.is_sharded(sockaddr) && getsockopt(fd, sockopt::ReusePort)? {
+          listener.shard = Some(0);
+          listeners.push(listener);
+
+          for i in 1..self.io_threads {
+            match takeover.as_mut().map_or(Ok(None), |t| t.take(sockaddr))? {
+              Some(fd) => {
+                let mut shard = Listener::take_over(fd, sockaddr.family(), self.socktype)?;
+                shard.id = id;
+                shard.shard = Some(i);
+                listeners.push(shard);
+              }
+              None => break,
+            }
+          }
+        } else {
+          listeners.push(listener);
+        }
+
+        continue;
+      }
+
+      // socket activation stands in for the configured address only
+      let inherited = match self.activation {
+        Some(ref activation) if i == 0 => activation::take(activation)?,
+        _ => None,
+      };
+
+      listeners.push(match inherited {
+        Some(fd) => {
+          let mut listener = Listener::adopt(fd, self.family, self.socktype)?;
+          listener.id = id;
+          listener
+        }
+        None => self.open(id, *sockaddr)?,
+      });
+    }
+
+    Ok(listeners)
+  }
+
+  fn open(&self, id: usize, sockaddr: SockAddr) -> Result<Listener> {
+    let mut listener = Listener::new(sockaddr, self.socktype, self.sockflag, self.sockproto)?;
+    listener.id = id;
+    self.socket_options.apply(listener.fd)?;
+
+    if self.is_sharded(&sockaddr) {
+      listener.reuse_port(0)?;
+    }
+
+    Ok(listener)
+  }
+
+  fn is_sharded(&self, sockaddr: &SockAddr) -> bool {
+    !matches!((self.accept_mode, *sockaddr),
+              (AcceptMode::Shared, _) | (_, SockAddr::Unix(_)))
+  }
+
+  /// Open the `SO_REUSEPORT` sockets of the I/O loops `from..io_threads`
+  /// alongside `first`.
+  fn open_shards(&self, first: &Listener, from: usize) -> Result<Vec<Listener>> {
+    let mut shards = Vec::with_capacity(self.io_threads.saturating_sub(from));
+
+    for i in from..self.io_threads {
+      let shard = first.shard(i, self.sockflag, self.sockproto, &self.socket_options)?;
+      shard.listen(self.backlog)?;
+      shards.push(shard);
+    }
+
+    Ok(shards)
+  }
+
+  /// Set up the steering of connections over a complete `SO_REUSEPORT` group,
+  /// for I/O loops pinned to `cpus`.
+  fn steer(&self, group: &[&Listener], cpus: Option<&[usize]>) -> Result<()> {
+    match self.accept_mode {
+      AcceptMode::ReusePort(Steering::IncomingCpu) => {
+        for listener in group {
+          if let Some(i) = listener.shard {
+            // unpinned loops have no CPU of their own, spread them anyway
+            let cpu = cpus.map_or(i % ::num_cpus::get(), |cpus| cpus[i]);
+            reuseport::set_incoming_cpu(listener.fd, cpu)?;
+          }
+        }
+      }
+      AcceptMode::ReusePort(Steering::Cbpf) => {
+        if let Some(first) = group.first() {
+          reuseport::attach_cpu_program(first.fd, self.io_threads, cpus)?;
+        }
+      }
+      AcceptMode::ReusePort(Steering::Hash) |
+      AcceptMode::Shared => {}
+    }
+
+    Ok(())
+  }
+
   fn inet<A: ToSocketAddrs>(addr: A) -> Result<(SockAddr, AddressFamily)> {
     let inet_addr_std = addr.to_socket_addrs()
       .unwrap()
@@ -107,53 +360,382 @@ impl<H> Server<H> {
     where F: FnOnce(EpollFd) -> H,
   {
 
-    let ServerConfig { io_threads,
-                       max_conn,
-                       socktype,
-                       sockaddr,
-                       sockflag,
-                       sockproto,
-                       family,
-                       epoll_config } = config;
-
-    let fd = epoll_create()?;
-
-    let epfd = EpollFd { fd: fd };
+    let mut takeover = match config.handoff {
+      Some(ref path) => Takeover::connect(path, handoff::timeout(Notifier::watchdog_from_env()?))?,
+      None => None,
+    };
 
-    let srvfd = socket(family, socktype, sockflag, sockproto)? as i32;
+    let listeners = config.listeners(0, &mut takeover)?;
 
-    setsockopt(srvfd, sockopt::ReuseAddr, &true).unwrap();
+    let epfd = EpollFd::create(config.epoll_config.backend)?;
 
     Ok(Server {
-      sockaddr: sockaddr,
+      listeners: listeners,
+      handler: new_handler(epfd.clone()),
       epfd: epfd,
-      srvfd: srvfd,
-      handler: new_handler(epfd),
-      max_conn: max_conn,
-      io_threads: io_threads,
-      epoll_config: epoll_config,
+      configs: vec![config],
+      source: None,
+      init: None,
+      takeover: takeover,
+      cpus: None,
+      epfds: Vec::new(),
+      handles: Vec::new(),
+      reconfigure: None,
+      metrics: ServerMetrics::default(),
+      threads: Vec::new(),
+      panics: Arc::new(PanicFd::new()?),
     })
   }
+
+  /// Where `reload` gets the configurations to apply, one per listener in the
+  /// order they were added, e.g. a function re-reading the configuration file.
+  ///
+  /// The socket types and number of I/O threads cannot change on reload.
+  pub fn reload_with<F>(self, source: F) -> Server<H>
+    where F: FnMut() -> Result<Vec<ServerConfig>> + Send + 'static,
+  {
+    Server { source: Some(Box::new(source)), ..self }
+  }
+
+  /// Accept queues of the listening sockets, the system-wide counters of
+  /// connections dropped on full ones and the counters of the I/O loops.
+  pub fn stats(&self) -> ServerStats {
+    let listeners = self.listeners
+      .iter()
+      .map(|l| {
+        let tcp = self.configs[l.id].socktype == SockType::Stream &&
+                  matches!(l.sockaddr, SockAddr::Inet(_));
+
+        let queue = if tcp {
+          stats::accept_queue(l.fd).map_err(|e| report_err!(e)).ok()
+        } else {
+          None
+        };
+
+        ListenerStats {
+          id: l.id,
+          sockaddr: l.sockaddr,
+          shard: l.shard,
+          queued: queue.map(|q| q.0),
+          backlog: queue.map(|q| q.1),
+        }
+      })
+      .collect();
+
+    let counters = match stats::listen_counters() {
+      Ok(counters) => Some(counters),
+      Err(e) => {
+        debug!("stats: {}", e);
+        None
+      }
+    };
+
+    ServerStats {
+      listeners: listeners,
+      listen_overflows: counters.map(|c| c.0),
+      listen_drops: counters.map(|c| c.1),
+      loops: self.metrics.loops(),
+    }
+  }
+
+  /// Counters of the I/O loops and connections, to read from other threads.
+  pub fn metrics(&self) -> ServerMetrics {
+    self.metrics.clone()
+  }
+
+  /// Run `init` with the index of each I/O loop on its thread before the loop
+  /// starts, e.g. to set up thread-locals. The thread of loop 0 is the one
+  /// running the event loop returned by `setup`.
+  pub fn on_thread_start<F>(self, init: F) -> Server<H>
+    where F: Fn(usize) + Send + Sync + 'static,
+  {
+    Server { init: Some(Arc::new(init)), ..self }
+  }
+
+  /// Open the listening sockets of another listener, returning its index.
+  fn add_config(&mut self, config: ServerConfig) -> Result<usize> {
+    let id = self.configs.len();
+
+    if id >= MAX_LISTENERS {
+      return Err(format!("cannot add more than {} listeners", MAX_LISTENERS).into());
+    }
+
+    let config = ServerConfig { io_threads: self.configs[0].io_threads, ..config };
+
+    let listeners = config.listeners(id, &mut self.takeover)?;
+    self.listeners.extend(listeners);
+    self.configs.push(config);
+
+    Ok(id)
+  }
 }
 
 impl<'m, H, F, R> Server<SyncMux<'m, H, F, R>>
   where H: Handler<MuxEvent<'m, R>, MuxCmd> + EpollHandler,
         F: HandlerFactory<'m, H, R> + 'm,
-        R: Clone + 'm,
+        R: Reset + Clone + 'm,
 {
   pub fn new(config: ServerConfig, factory: F) -> Result<Server<SyncMux<'m, H, F, R>>> {
 
-    let max_conn = config.max_conn;
-    let server = Server::new_with(config, |epfd| SyncMux::new(max_conn, epfd, factory))?;
+    let mux_config = config.mux_config;
+    let mut server = Server::new_with(config, |epfd| SyncMux::new(mux_config, epfd, factory))?;
+    server.metrics.mux = Some(server.handler.metrics());
+    server.reconfigure = Some(SyncMux::reconfigure);
 
     Ok(server)
   }
+
+  /// Accept connections on the addresses of `config` as well, with handlers and
+  /// resources from `factory`, before `setup`. Handlers see the index of the
+  /// listener, 0 being the one given to `new`, as `MuxEvent::listener`.
+  /// Factories of different kinds are passed as variants of one enum `F`.
+  ///
+  /// The I/O threads, event loop and connection settings, drain timeout and
+  /// handoff of the first configuration apply to all listeners.
+  pub fn listen(mut self, config: ServerConfig, factory: F) -> Result<Server<SyncMux<'m, H, F, R>>> {
+    let id = self.add_config(config)?;
+    let added = self.handler.add_factory(factory);
+    debug_assert_eq!(id, added);
+
+    Ok(self)
+  }
+
+  /// Connection counters summed over the I/O loops.
+  pub fn mux_stats(&self) -> MuxStats {
+    self.handler.metrics().snapshot()
+  }
+}
+
+/// Counters of the I/O loops and connections of a `Server`, readable from
+/// other threads, e.g. to serve them with `metrics::Metrics`.
+#[derive(Clone, Default)]
+pub struct ServerMetrics {
+  loops: Arc<Mutex<Vec<Arc<EpollCounters>>>>,
+  mux: Option<MuxMetrics>,
+}
+
+impl ServerMetrics {
+  /// Counters of each I/O loop, empty unless `EpollConfig::stats` was set on `setup`.
+  pub fn loops(&self) -> Vec<EpollStats> {
+    self.loops.lock().unwrap().iter().map(|c| c.snapshot()).collect()
+  }
+
+  /// Connection counters summed over the I/O loops, for `SyncMux` handlers.
+  pub fn connections(&self) -> Option<MuxStats> {
+    self.mux.as_ref().map(|mux| mux.snapshot())
+  }
+
+  /// Write the counters in the Prometheus text format.
+  pub fn write(&self, out: &mut String) {
+    metrics::write_loops(out, &self.loops());
+
+    if let Some(stats) = self.connections() {
+      metrics::write_connections(out, &stats);
+    }
+  }
+}
+
+impl<H: EpollHandler> Server<H> {
+  /// Interest of the event loops in a listening socket.
+  fn interest(listener: &Listener) -> EpollEvent {
+    EpollEvent {
+      events: H::interests(),
+      data: Action::encode(Action::New(listener.fd, listener.id)),
+    }
+  }
+
+  /// Register `listeners` with the event loops that accept on them.
+  fn register(&self, listeners: &[Listener]) -> Result<()> {
+    for (i, epfd) in self.epfds.iter().enumerate() {
+      for listener in listeners.iter().filter(|l| l.serves(i)) {
+        epfd.register(listener.fd, &Self::interest(listener))?;
+        debug!("registered I/O loop {} interest on {}", i, listener.fd);
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl<H> Server<H>
+  where H: EpollHandler + 'static,
+{
+  /// Open a socket of the listener `id` for `sockaddr`, sharded if configured so,
+  /// and register it with the event loops.
+  fn add_listener(&mut self, id: usize, sockaddr: SockAddr) -> Result<()> {
+    let config = &self.configs[id];
+
+    let mut listener = config.open(id, sockaddr)?;
+    listener.bind(config.unix_mode, config.unix_owner)?;
+    listener.listen(config.backlog)?;
+
+    let mut group = match listener.shard {
+      Some(_) => config.open_shards(&listener, 1)?,
+      None => Vec::new(),
+    };
+    group.insert(0, listener);
+
+    if group[0].shard.is_some() {
+      config.steer(&group.iter().collect::<Vec<_>>(), self.cpus.as_deref())?;
+    }
+
+    if let Err(e) = self.register(&group) {
+      for listener in group {
+        self.retire(listener);
+      }
+      return Err(e);
+    }
+
+    info!("reload: listening on {}", sockaddr);
+    self.listeners.extend(group);
+
+    Ok(())
+  }
+
+  /// Stop accepting on `listener`. Its socket is closed once every
+  /// event loop is done with the events already read for it.
+  fn retire(&self, listener: Listener) {
+    let listener = Arc::new(listener);
+
+    for epfd in &self.epfds {
+      // ENOENT if it was never registered with this loop
+      let _ = epfd.unregister(listener.fd);
+    }
+
+    for handle in &self.handles {
+      let listener = listener.clone();
+      if let Err(e) = handle.spawn(move |h| h.retire(listener.fd)) {
+        report_err!(e);
+      }
+    }
+  }
+
+  /// Apply `configs`, one per listener, without dropping open connections.
+  fn apply(&mut self, configs: Vec<ServerConfig>) {
+    if configs.len() != self.configs.len() {
+      error!("reload: {} configurations for {} listeners, keeping the current configuration",
+             configs.len(),
+             self.configs.len());
+      return;
+    }
+
+    if configs.iter().zip(&self.configs).any(|(new, old)| new.socktype != old.socktype) {
+      error!("reload: socket type cannot change, keeping the current configuration");
+      return;
+    }
+
+    if configs[0].io_threads != self.configs[0].io_threads {
+      warn!("reload: ignoring change of I/O threads from {} to {}",
+            self.configs[0].io_threads,
+            configs[0].io_threads);
+    }
+
+    if configs[0].epoll_config.backend != self.configs[0].epoll_config.backend {
+      warn!("reload: ignoring change of backend from {:?} to {:?}",
+            self.configs[0].epoll_config.backend,
+            configs[0].epoll_config.backend);
+    }
+
+    for (id, config) in configs.into_iter().enumerate() {
+      self.apply_listener(id, config);
+    }
+
+    let mux_config = self.configs[0].mux_config;
+    let epoll_config = self.configs[0].epoll_config;
+
+    let reconfigure = self.reconfigure;
+
+    for handle in &self.handles {
+      let res = match reconfigure {
+        Some(reconfigure) => handle.spawn(move |h| reconfigure(h, mux_config)),
+        None => Ok(()),
+      };
+
+      if let Err(e) = res.and_then(|_| handle.configure(epoll_config)) {
+        report_err!(e);
+      }
+    }
+  }
+
+  fn apply_listener(&mut self, id: usize, config: ServerConfig) {
+    if config.accept_mode != self.configs[id].accept_mode {
+      warn!("reload: ignoring change of accept mode from {:?} to {:?}",
+            self.configs[id].accept_mode,
+            config.accept_mode);
+    }
+
+    let backlog_changed = config.backlog != self.configs[id].backlog;
+    let io_threads = self.configs[id].io_threads;
+    let accept_mode = self.configs[id].accept_mode;
+
+    self.configs[id] = ServerConfig {
+      io_threads: io_threads,
+      accept_mode: accept_mode,
+      ..config
+    };
+
+    // adopted sockets stay as they are, and stand in for the configured address
+    let adopted = self.listeners.iter().any(|l| l.id == id && l.is_adopted());
+
+    let mut wanted = self.configs[id].sockaddrs.clone();
+    if !adopted {
+      wanted.insert(0, self.configs[id].sockaddr);
+    }
+
+    let mut i = 0;
+    while i < self.listeners.len() {
+      let listener = &self.listeners[i];
+      if listener.id != id || listener.is_adopted() || wanted.contains(&listener.sockaddr) {
+        i += 1;
+      } else {
+        let listener = self.listeners.remove(i);
+        if listener.shard.map_or(true, |shard| shard == 0) {
+          info!("reload: stopped listening on {}", listener.sockaddr);
+        }
+        self.retire(listener);
+      }
+    }
+
+    for sockaddr in wanted {
+      if self.listeners.iter().any(|l| l.id == id && l.sockaddr == sockaddr) {
+        continue;
+      }
+
+      if let Err(e) = self.add_listener(id, sockaddr) {
+        error!("reload: could not listen on {}: {}", sockaddr, e);
+      }
+    }
+
+    if backlog_changed {
+      for listener in self.listeners.iter().filter(|l| l.id == id) {
+        if let Err(e) = listener.listen(self.configs[id].backlog) {
+          report_err!(e);
+        }
+      }
+    }
+  }
 }
 
-impl<H> Reload for Server<H> {
-  // TODO reload configuration
-  // should assert that is not multithreaded + reload needed
-  fn reload(&mut self) {}
+impl<H> Reload for Server<H>
+  where H: EpollHandler + 'static,
+{
+  /// Re-read the configuration from the source given to `reload_with`, then
+  /// apply it to the listeners and event loops. Errors are logged and leave
+  /// the server running.
+  fn reload(&mut self) {
+    let config = match self.source {
+      Some(ref mut source) => source(),
+      None => {
+        info!("reload: no configuration source");
+        return;
+      }
+    };
+
+    match config {
+      Ok(configs) => self.apply(configs),
+      Err(e) => error!("reload: {}", e),
+    }
+  }
 }
 
 impl<H> Prop for Server<H>
@@ -163,69 +745,542 @@ impl<H> Prop for Server<H>
 
   fn setup(&mut self, mask: SigSet) -> Result<Epoll<Self::EpollHandler>> {
 
-    syscall!(bind(self.srvfd, &self.sockaddr))?;
-    info!("bind: fd {} to {}", self.srvfd, self.sockaddr);
+    for listener in &mut self.listeners {
+      let config = &self.configs[listener.id];
+      listener.bind(config.unix_mode, config.unix_owner)?;
+      listener.listen(config.backlog)?;
+    }
 
-    syscall!(listen(self.srvfd, self.max_conn))?;
-    info!("listen: fd {} with max connections: {}", self.srvfd, self.max_conn);
+    let io_threads = self.configs[0].io_threads;
+    let cpus = self.configs[0].affinity.cpus(io_threads)?;
 
-    let ceinfo = EpollEvent {
-      events: H::interests(),
-      data: self.srvfd as u64,
-    };
+    // open the shards that were not handed off
+    let mut shards = Vec::new();
+    for first in self.listeners.iter().filter(|l| l.shard == Some(0)) {
+      let taken = self.listeners
+        .iter()
+        .filter(|l| l.shard.is_some() && l.id == first.id && l.sockaddr == first.sockaddr)
+        .count();
+      shards.extend(self.configs[first.id].open_shards(first, taken)?);
+    }
+    self.listeners.extend(shards);
 
-    self.epfd.register(self.srvfd, &ceinfo)?;
-    debug!("registered main interest on {}", self.srvfd);
+    for first in self.listeners.iter().filter(|l| l.shard == Some(0)) {
+      let group: Vec<&Listener> = self.listeners
+        .iter()
+        .filter(|l| l.shard.is_some() && l.id == first.id && l.sockaddr == first.sockaddr)
+        .collect();
+      self.configs[first.id].steer(&group, cpus.as_deref())?;
+    }
 
-    let io_threads = self.io_threads;
-    let epoll_config = self.epoll_config;
-    let srvfd = self.srvfd;
+    let epoll_config = self.configs[0].epoll_config;
 
-    for i in 1..io_threads {
+    let mut epoll = Epoll::from_fd(self.epfd.clone(), self.handler.clone(), epoll_config);
 
-      let epfd = EpollFd::new(epoll_create()?);
+    self.handles.push(epoll.handle()?);
+    self.epfds.push(self.epfd.clone());
 
-      epfd.register(srvfd, &ceinfo)?;
-      debug!("registered thread {} interest on {}", i, srvfd);
+    let mut counters = vec![epoll.counters()];
+
+    let mut loops = Vec::with_capacity(io_threads - 1);
+
+    for _ in 1..io_threads {
+
+      let epfd = EpollFd::create(epoll_config.backend)?;
 
       let mut handler = self.handler.clone();
 
-      handler.with_epfd(epfd);
+      handler.with_epfd(epfd.clone());
+
+      let mut epoll = Epoll::from_fd(epfd.clone(), handler, epoll_config);
+
+      self.handles.push(epoll.handle()?);
+      self.epfds.push(epfd);
+      counters.push(epoll.counters());
+
+      loops.push(epoll);
+    }
+
+    if epoll_config.stats {
+      *self.metrics.loops.lock().unwrap() = counters;
+    }
+
+    self.register(&self.listeners)?;
+
+    for (i, mut epoll) in (1..).zip(loops) {
+
+      let cpu = cpus.as_ref().map(|cpus| cpus[i]);
+      let init = self.init.clone();
+      let panics = self.panics.clone();
+
+      let t = thread::Builder::new().name(format!("rux-io-{}", i)).spawn(move || {
+        let _guard = PanicFd::guard(&panics);
 
-      thread::spawn(move || {
         // add the set of signals to the signal mask for all threads
         mask.thread_block().unwrap();
 
-        let mut epoll = Epoll::from_fd(epfd, handler, epoll_config);
+        if let Some(cpu) = cpu {
+          pin(i, cpu);
+        }
 
-        let aff = i % ::num_cpus::get();
-        let mut cpuset = sched::CpuSet::new();
-        cpuset.set(aff).unwrap();
+        if let Some(init) = init {
+          init(i);
+        }
 
-        // TODO optional
-        sched::sched_setaffinity(0, &cpuset).unwrap();
+        info!("starting I/O thread {} event loop", i);
 
-        debug!("set thread {} affinity to cpu {}", i, aff);
+        match epoll.run() {
+          Ok(()) => info!("stopped I/O thread {} event loop", i),
+          Err(e) => {
+            error!("I/O thread {} event loop failed", i);
+            report_err!(e);
+            panics.signal();
+          }
+        }
+      })?;
 
-        info!("starting I/O thread {} event loop", i);
+      self.threads.push(t);
+    }
+
+    debug!("created {} I/O epoll instances", io_threads);
+    info!("starting I/O thread 0 event loop");
+
+    // the calling thread runs the I/O loop 0
+    if let Some(ref cpus) = cpus {
+      pin(0, cpus[0]);
+    }
+
+    self.cpus = cpus;
+
+    if let Some(init) = self.init.clone() {
+      epoll.before_run(move |_| init(0));
+    }
+
+    if let Some(takeover) = self.takeover.take() {
+      takeover.complete()?;
+    }
+
+    Ok(epoll)
+  }
+
+  fn shutdown(&mut self) {
+    for listener in &self.listeners {
+      info!("shutdown: stop accepting on fd {}", listener.fd);
+
+      for (_, epfd) in self.epfds.iter().enumerate().filter(|&(i, _)| listener.serves(i)) {
+        if let Err(e) = epfd.unregister(listener.fd) {
+          match *e.kind() {
+            // paused by a loop with no free slots
+            ErrorKind::NixError(NixError::Sys(errno::ENOENT)) => {}
+            _ => report_err!(e),
+          }
+        }
+      }
+    }
+
+    let drain_timeout = self.configs[0].drain_timeout;
+
+    info!("shutdown: draining {} I/O event loops for up to {:?}",
+          self.handles.len(),
+          drain_timeout);
+
+    for handle in self.handles.drain(..) {
+      if let Err(e) = handle.spawn(|h| h.drain()).and_then(|_| handle.shutdown(drain_timeout)) {
+        report_err!(e);
+      }
+    }
+
+    for (i, t) in self.threads.drain(..).enumerate() {
+      if t.join().is_err() {
+        error!("I/O thread {} panicked", i + 1);
+      }
+    }
+
+    // closes the sockets and removes the socket files of unix listeners
+    self.listeners.clear();
+  }
+
+  fn listen_fds(&self) -> Vec<RawFd> {
+    self.listeners.iter().map(|l| l.fd).collect()
+  }
+
+  fn handed_off(&mut self) {
+    for listener in &mut self.listeners {
+      listener.hand_off();
+    }
+  }
+
+  fn panics(&self) -> Option<Arc<PanicFd>> {
+    Some(self.panics.clone())
+  }
+}
+
+/// Pin the thread of the I/O loop `i` to `cpu`, or leave it to the scheduler.
+fn pin(i: usize, cpu: usize) {
+  match affinity::pin(cpu) {
+    Ok(()) => debug!("set thread {} affinity to cpu {}", i, cpu),
+    Err(e) => {
+      error!("could not pin I/O thread {} to cpu {}", i, cpu);
+      report_err!(e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use RawFd;
+  use Reset;
+  use epoll::*;
+  use handler::*;
+  use mux::*;
+  use nix::sys::socket::*;
+  use prop::{Prop, Reload};
+  use prop::reuseport::{AcceptMode, Steering};
+  use std::io::{Read, Write};
+  use std::net::{self, TcpStream};
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use super::*;
+
+  #[derive(Clone)]
+  struct Buf;
+
+  impl Reset for Buf {
+    fn reset(&mut self) {}
+  }
+
+  struct Echo {
+    closed: bool,
+  }
+
+  impl<'a> Handler<MuxEvent<'a, Buf>, MuxCmd> for Echo {
+    fn next(&mut self) -> MuxCmd {
+      if self.closed {
+        return MuxCmd::Close;
+      }
+
+      MuxCmd::Keep
+    }
+
+    fn on_next(&mut self, event: MuxEvent<'a, Buf>) {
+      if event.events.intersects(EPOLLRDHUP | EPOLLHUP) {
+        self.closed = true;
+        return;
+      }
+
+      let mut buf = [0; 64];
+      if let Ok(Some(n)) = syscall!(recv(event.fd, &mut buf, MSG_DONTWAIT)) {
+        event.counters.bytes_in(n);
+        let n = send(event.fd, &buf[..n], MSG_DONTWAIT).unwrap();
+        event.counters.bytes_out(n);
+      }
+    }
+  }
+
+  impl EpollHandler for Echo {
+    fn interests() -> EpollEventKind {
+      EPOLLIN | EPOLLRDHUP | EPOLLET
+    }
+
+    fn with_epfd(&mut self, _: EpollFd) {}
+  }
+
+  #[derive(Clone)]
+  struct EchoFactory;
+
+  impl<'a> HandlerFactory<'a, Echo, Buf> for EchoFactory {
+    fn new_handler(&mut self, _: EpollFd, _: RawFd) -> Echo {
+      Echo { closed: false }
+    }
+
+    fn new_resource(&self) -> Buf {
+      Buf
+    }
+  }
+
+  /// Replies with its factory's tag and the listener of the connection.
+  struct Tag {
+    tag: u8,
+    closed: bool,
+  }
+
+  impl<'a> Handler<MuxEvent<'a, Buf>, MuxCmd> for Tag {
+    fn next(&mut self) -> MuxCmd {
+      if self.closed {
+        return MuxCmd::Close;
+      }
+
+      MuxCmd::Keep
+    }
+
+    fn on_next(&mut self, event: MuxEvent<'a, Buf>) {
+      if event.events.intersects(EPOLLRDHUP | EPOLLHUP) {
+        self.closed = true;
+        return;
+      }
+
+      let mut buf = [0; 64];
+      if let Ok(Some(_)) = syscall!(recv(event.fd, &mut buf, MSG_DONTWAIT)) {
+        send(event.fd, &[self.tag, event.listener.unwrap() as u8], MSG_DONTWAIT).unwrap();
+      }
+    }
+  }
+
+  impl EpollHandler for Tag {
+    fn interests() -> EpollEventKind {
+      EPOLLIN | EPOLLRDHUP | EPOLLET
+    }
+
+    fn with_epfd(&mut self, _: EpollFd) {}
+  }
+
+  #[derive(Clone)]
+  struct TagFactory(u8);
+
+  impl<'a> HandlerFactory<'a, Tag, Buf> for TagFactory {
+    fn new_handler(&mut self, _: EpollFd, _: RawFd) -> Tag {
+      Tag {
+        tag: self.0,
+        closed: false,
+      }
+    }
+
+    fn new_resource(&self) -> Buf {
+      Buf
+    }
+  }
+
+  fn local_addrs<H>(server: &Server<H>) -> Vec<net::SocketAddr> {
+    server.listeners
+      .iter()
+      .map(|l| {
+        match getsockname(l.fd).unwrap() {
+          SockAddr::Inet(addr) => addr.to_std(),
+          _ => unreachable!(),
+        }
+      })
+      .collect()
+  }
+
+  fn echo(stream: &mut TcpStream) {
+    stream.write_all(b"ping").unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+  }
+
+  #[test]
+  fn reloads_listen_addresses() {
+    let config = ServerConfig::tcp("127.0.0.1:0").unwrap().io_threads(1).backlog(16);
+
+    let next = Arc::new(Mutex::new(None));
+    let source = next.clone();
+
+    let mut server = Server::new(config, EchoFactory)
+      .unwrap()
+      .reload_with(move || source.lock().unwrap().take().ok_or_else(|| "no config".into()));
+
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    let old = local_addrs(&server)[0];
+    let mut stream = TcpStream::connect(old).unwrap();
+    echo(&mut stream);
+
+    let stats = server.stats();
+    assert_eq!(stats.listeners.len(), 1);
+    assert_eq!(stats.listeners[0].queued, Some(0));
+    assert_eq!(stats.listeners[0].backlog, Some(16));
+    assert!(stats.loops.is_empty());
+
+    // a failing source leaves the server as it is
+    server.reload();
+    assert_eq!(local_addrs(&server), vec![old]);
+
+    let config = ServerConfig::tcp("127.0.0.2:0").unwrap().io_threads(1).backlog(8).max_conn(8);
+    *next.lock().unwrap() = Some(vec![config]);
+    server.reload();
+
+    let addrs = local_addrs(&server);
+    assert_eq!(addrs.len(), 1);
+    assert!(addrs[0] != old);
+    assert_eq!(server.stats().listeners[0].backlog, Some(8));
+
+    echo(&mut stream);
+    echo(&mut TcpStream::connect(addrs[0]).unwrap());
+    assert!(TcpStream::connect(old).is_err());
+
+    drop(stream);
+    server.shutdown();
+    main.join().unwrap().unwrap();
+  }
+
+  /// Event loop handler of its own, not a `SyncMux`.
+  #[derive(Clone)]
+  struct Idle;
+
+  impl Handler<EpollEvent, EpollCmd> for Idle {
+    fn next(&mut self) -> EpollCmd {
+      EpollCmd::Poll
+    }
+
+    fn on_next(&mut self, _: EpollEvent) {}
+  }
+
+  impl EpollHandler for Idle {
+    fn interests() -> EpollEventKind {
+      EPOLLIN
+    }
+
+    fn with_epfd(&mut self, _: EpollFd) {}
+  }
+
+  #[test]
+  fn reloads_servers_of_any_handler() {
+    // Idle does not drain
+    let config = ServerConfig::tcp("127.0.0.1:0")
+      .unwrap()
+      .io_threads(1)
+      .drain_timeout(Duration::from_millis(10));
+
+    let next = Arc::new(Mutex::new(None));
+    let source = next.clone();
+
+    let mut server = Server::new_with(config, |_| Idle)
+      .unwrap()
+      .reload_with(move || source.lock().unwrap().take().ok_or_else(|| "no config".into()));
+
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    let config = ServerConfig::tcp("127.0.0.1:0")
+      .unwrap()
+      .io_threads(1)
+      .backlog(8)
+      .drain_timeout(Duration::from_millis(10));
+    *next.lock().unwrap() = Some(vec![config]);
+    Reload::reload(&mut server);
+
+    assert_eq!(server.stats().listeners[0].backlog, Some(8));
+
+    server.shutdown();
+    main.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn routes_connections_per_listener() {
+    let data = ServerConfig::tcp("127.0.0.1:0").unwrap().io_threads(2);
+    let admin = ServerConfig::tcp("127.0.0.1:0").unwrap().max_conn(4);
+
+    let mut server = Server::new(data, TagFactory(b'd'))
+      .unwrap()
+      .listen(admin, TagFactory(b'a'))
+      .unwrap();
+
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    let ids: Vec<usize> = server.listeners.iter().map(|l| l.id).collect();
+    assert_eq!(ids, vec![0, 1]);
+
+    let addrs = local_addrs(&server);
+
+    for _ in 0..4 {
+      for (addr, reply) in addrs.iter().zip(&[[b'd', 0], [b'a', 1]]) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"?").unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, reply);
+      }
+    }
+
+    server.shutdown();
+    main.join().unwrap().unwrap();
+  }
+
+  #[test]
+  fn runs_thread_init_on_named_threads() {
+    let config = ServerConfig::tcp("127.0.0.1:0").unwrap().io_threads(2);
 
-        epoll.run();
+    let started = Arc::new(Mutex::new(Vec::new()));
+    let log = started.clone();
+
+    let mut server = Server::new(config, EchoFactory)
+      .unwrap()
+      .on_thread_start(move |i| {
+        let name = thread::current().name().map(|n| n.to_owned());
+        log.lock().unwrap().push((i, name));
       });
+
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    echo(&mut TcpStream::connect(local_addrs(&server)[0]).unwrap());
+
+    server.shutdown();
+    main.join().unwrap().unwrap();
+
+    let mut started = started.lock().unwrap().clone();
+    started.sort();
+    assert_eq!(started, vec![(0, None), (1, Some("rux-io-1".to_owned()))]);
+  }
+
+  #[test]
+  fn shards_listeners_per_thread() {
+    let config = ServerConfig::tcp("127.0.0.1:0")
+      .unwrap()
+      .io_threads(2)
+      .accept_mode(AcceptMode::ReusePort(Steering::Cbpf));
+
+    let mut server = Server::new(config, EchoFactory).unwrap();
+
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
+
+    let shards: Vec<Option<usize>> = server.listeners.iter().map(|l| l.shard).collect();
+    assert_eq!(shards, vec![Some(0), Some(1)]);
+
+    let addrs = local_addrs(&server);
+    assert_eq!(addrs[0], addrs[1]);
+
+    for _ in 0..8 {
+      echo(&mut TcpStream::connect(addrs[0]).unwrap());
     }
 
-    let epoll = Epoll::from_fd(self.epfd, self.handler.clone(), epoll_config);
+    server.shutdown();
+    main.join().unwrap().unwrap();
 
-    debug!("created {} I/O epoll instances", self.io_threads);
-    info!("starting I/O thread 0 event loop");
+    // summed over both loops, which drained all connections
+    let stats = server.mux_stats();
+    assert_eq!((stats.accepts, stats.active, stats.hangups), (8, 0, 8));
+    assert_eq!((stats.bytes_in, stats.bytes_out), (32, 32));
+    assert!(stats.accept_errors.is_empty());
+  }
 
-    let aff = 0;
-    let mut cpuset = sched::CpuSet::new();
-    cpuset.set(aff).unwrap();
+  #[test]
+  fn echoes_on_io_uring() {
+    let config = ServerConfig::tcp("127.0.0.1:0").unwrap().io_threads(2).backend(Backend::Uring);
 
-    sched::sched_setaffinity(0, &cpuset).unwrap();
+    let mut server = Server::new(config, EchoFactory).unwrap();
 
-    debug!("set thread 0 affinity to cpu {}", aff);
+    let mut epoll = server.setup(SigSet::empty()).unwrap();
+    let main = thread::spawn(move || epoll.run());
 
-    Ok(epoll)
+    let addr = local_addrs(&server)[0];
+    let mut streams: Vec<TcpStream> = (0..8).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+    for stream in &mut streams {
+      echo(stream);
+      echo(stream);
+    }
+
+    drop(streams);
+    server.shutdown();
+    main.join().unwrap().unwrap();
+
+    let stats = server.mux_stats();
+    assert_eq!((stats.accepts, stats.active, stats.hangups), (8, 0, 8));
+    assert_eq!((stats.bytes_in, stats.bytes_out), (64, 64));
   }
 }
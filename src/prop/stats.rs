@@ -0,0 +1,161 @@
+use RawFd;
+use epoll::EpollStats;
+use error::*;
+use libc_sys::{self, c_void, socklen_t};
+use nix::Errno;
+use nix::sys::socket::SockAddr;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+
+const TCP_LISTEN: u8 = 10;
+
+/// Snapshot of the accept queues and I/O loops of a `Server`.
+#[derive(Clone, PartialEq)]
+pub struct ServerStats {
+  pub listeners: Vec<ListenerStats>,
+  /// Connections dropped by the system because an accept queue was full,
+  /// `ListenOverflows` in `/proc/net/netstat`. Counts every listening socket
+  /// of the network namespace, not only the server's.
+  pub listen_overflows: Option<u64>,
+  /// Connections dropped by the system before being accepted for any reason,
+  /// overflows included, `ListenDrops` in `/proc/net/netstat`
+  pub listen_drops: Option<u64>,
+  /// Counters of each I/O loop, empty unless `EpollConfig::stats` was set on `setup`
+  pub loops: Vec<EpollStats>,
+}
+
+/// Accept queue of one listening socket.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ListenerStats {
+  /// Index of the listener in the `Server`
+  pub id: usize,
+  pub sockaddr: SockAddr,
+  /// I/O loop accepting on the socket, `None` if all do
+  pub shard: Option<usize>,
+  /// Connections waiting to be accepted, for TCP sockets
+  pub queued: Option<u32>,
+  /// Length of the accept queue, for TCP sockets
+  pub backlog: Option<u32>,
+}
+
+/// Head of `struct tcp_info`; the kernel fills in as much as it is given.
+#[repr(C)]
+#[derive(Default)]
+struct tcp_info {
+  tcpi_state: u8,
+  tcpi_ca_state: u8,
+  tcpi_retransmits: u8,
+  tcpi_probes: u8,
+  tcpi_backoff: u8,
+  tcpi_options: u8,
+  tcpi_wscale: u8,
+  tcpi_flags: u8,
+  tcpi_rto: u32,
+  tcpi_ato: u32,
+  tcpi_snd_mss: u32,
+  tcpi_rcv_mss: u32,
+  /// Accept queue length of a listening socket
+  tcpi_unacked: u32,
+  /// Backlog of a listening socket
+  tcpi_sacked: u32,
+}
+
+/// Connections waiting to be accepted on the listening TCP socket `fd`
+/// and the length of its accept queue, from `TCP_INFO`.
+pub fn accept_queue(fd: RawFd) -> Result<(u32, u32)> {
+  let mut info = tcp_info::default();
+  let mut len = mem::size_of::<tcp_info>() as socklen_t;
+
+  let res = unsafe {
+    libc_sys::getsockopt(fd,
+                         libc_sys::IPPROTO_TCP,
+                         libc_sys::TCP_INFO,
+                         &mut info as *mut tcp_info as *mut c_void,
+                         &mut len)
+  };
+  Errno::result(res)?;
+
+  if info.tcpi_state != TCP_LISTEN {
+    return Err(format!("fd {} is not a listening TCP socket", fd).into());
+  }
+
+  Ok((info.tcpi_unacked, info.tcpi_sacked))
+}
+
+/// `ListenOverflows` and `ListenDrops` of the network namespace.
+pub fn listen_counters() -> Result<(u64, u64)> {
+  let mut netstat = String::new();
+  File::open("/proc/net/netstat")?.read_to_string(&mut netstat)?;
+
+  match (netstat_counter(&netstat, "TcpExt", "ListenOverflows"),
+         netstat_counter(&netstat, "TcpExt", "ListenDrops")) {
+    (Some(overflows), Some(drops)) => Ok((overflows, drops)),
+    _ => Err("no listen counters in /proc/net/netstat".into()),
+  }
+}
+
+/// Counter `name` of the `group` in the `/proc/net/netstat` format: a line of
+/// names followed by a line of values, both prefixed with the group.
+fn netstat_counter(netstat: &str, group: &str, name: &str) -> Option<u64> {
+  let mut lines = netstat.lines();
+
+  while let Some(names) = lines.next() {
+    let values = lines.next()?;
+
+    let mut names = names.split_whitespace();
+    let mut values = values.split_whitespace();
+
+    if names.next()?.trim_end_matches(':') != group {
+      continue;
+    }
+    values.next()?;
+
+    let i = names.position(|n| n == name)?;
+    return values.nth(i)?.parse().ok();
+  }
+
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use nix::sys::socket::*;
+  use nix::unistd;
+  use std::net::TcpStream;
+  use super::*;
+
+  #[test]
+  fn reads_accept_queue() {
+    let fd = socket(AddressFamily::Inet, SockType::Stream, SOCK_CLOEXEC, 0).unwrap();
+    let sockaddr = SockAddr::Inet(InetAddr::from_std(&"127.0.0.1:0".parse().unwrap()));
+    bind(fd, &sockaddr).unwrap();
+    listen(fd, 8).unwrap();
+
+    let addr = match getsockname(fd).unwrap() {
+      SockAddr::Inet(addr) => addr.to_std(),
+      _ => unreachable!(),
+    };
+
+    let _streams: Vec<_> = (0..2).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    assert_eq!(accept_queue(fd).unwrap(), (2, 8));
+
+    unistd::close(accept(fd).unwrap()).unwrap();
+    assert_eq!(accept_queue(fd).unwrap(), (1, 8));
+
+    unistd::close(fd).unwrap();
+  }
+
+  #[test]
+  fn parses_netstat_counters() {
+    let netstat = "TcpExt: SyncookiesSent ListenOverflows ListenDrops\n\
+                   TcpExt: 1 2 3\n\
+                   IpExt: InNoRoutes ListenDrops\n\
+                   IpExt: 4 5\n";
+
+    assert_eq!(netstat_counter(netstat, "TcpExt", "ListenOverflows"), Some(2));
+    assert_eq!(netstat_counter(netstat, "TcpExt", "ListenDrops"), Some(3));
+    assert_eq!(netstat_counter(netstat, "IpExt", "ListenDrops"), Some(5));
+    assert_eq!(netstat_counter(netstat, "IpExt", "Missing"), None);
+  }
+}
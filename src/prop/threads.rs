@@ -0,0 +1,96 @@
+use RawFd;
+use error::*;
+use libc_sys::{eventfd, EFD_CLOEXEC, EFD_NONBLOCK};
+use nix::{unistd, Errno};
+use std::sync::Arc;
+use std::thread;
+
+/// Eventfd that becomes readable when a thread holding one of its guards panics,
+/// or signals it on failing otherwise.
+pub struct PanicFd {
+  pub fd: RawFd,
+}
+
+impl PanicFd {
+  pub fn new() -> Result<PanicFd> {
+    let fd = unsafe { Errno::result(eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK))? };
+    Ok(PanicFd { fd: fd })
+  }
+
+  /// Guard signalling the eventfd if the calling thread panics while it is alive.
+  pub fn guard(panics: &Arc<PanicFd>) -> PanicGuard {
+    PanicGuard { panics: panics.clone() }
+  }
+
+  /// Signal a failure of the calling thread.
+  pub fn signal(&self) {
+    if let Err(e) = syscall!(unistd::write(self.fd, &1_u64.to_ne_bytes())) {
+      report_err!(e);
+    }
+  }
+
+  /// Number of panics and failures since the last call.
+  pub fn read(&self) -> Result<u64> {
+    let mut buf = [0_u8; 8];
+
+    match syscall!(unistd::read(self.fd, &mut buf))? {
+      Some(_) => Ok(u64::from_ne_bytes(buf)),
+      None => Ok(0),
+    }
+  }
+}
+
+impl Drop for PanicFd {
+  fn drop(&mut self) {
+    let _ = unistd::close(self.fd);
+  }
+}
+
+pub struct PanicGuard {
+  panics: Arc<PanicFd>,
+}
+
+impl Drop for PanicGuard {
+  fn drop(&mut self) {
+    if !thread::panicking() {
+      return;
+    }
+
+    error!("thread {} panicked", thread::current().name().unwrap_or("<unnamed>"));
+    self.panics.signal();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::thread;
+  use super::*;
+
+  #[test]
+  fn signals_panicked_threads() {
+    let panics = Arc::new(PanicFd::new().unwrap());
+
+    let guard = PanicFd::guard(&panics);
+    thread::spawn(move || {
+        let _guard = guard;
+      })
+      .join()
+      .unwrap();
+
+    assert_eq!(panics.read().unwrap(), 0);
+
+    let guard = PanicFd::guard(&panics);
+    let res = thread::Builder::new()
+      .name("rux-test".to_owned())
+      .spawn(move || {
+        let _guard = guard;
+        panic!("test");
+      })
+      .unwrap()
+      .join();
+
+    assert!(res.is_err());
+    assert_eq!(panics.read().unwrap(), 1);
+  }
+}
@@ -0,0 +1,399 @@
+use RawFd;
+use epoll::*;
+use error::*;
+use handler::Handler;
+use libc_sys::{c_int, clockid_t, timespec, time_t, c_long, CLOCK_MONOTONIC, CLOCK_REALTIME,
+               CLOCK_BOOTTIME, O_CLOEXEC, O_NONBLOCK};
+use nix::{unistd, Errno};
+use std::collections::HashMap;
+use std::time::Duration;
+
+mod wheel;
+
+pub use self::wheel::TimingWheel;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct itimerspec {
+  it_interval: timespec,
+  it_value: timespec,
+}
+
+extern "C" {
+  fn timerfd_create(clockid: clockid_t, flags: c_int) -> c_int;
+  fn timerfd_settime(fd: c_int, flags: c_int, new_value: *const itimerspec,
+                     old_value: *mut itimerspec)
+                     -> c_int;
+}
+
+const TFD_NONBLOCK: c_int = O_NONBLOCK;
+const TFD_CLOEXEC: c_int = O_CLOEXEC;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ClockId {
+  Realtime,
+  Monotonic,
+  Boottime,
+}
+
+/// Expiration schedule of a timer.
+///
+/// `Oneshot` fires once after the given delay; `Periodic` fires after `delay`
+/// and then every `interval` until the timer is disarmed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimerSpec {
+  Oneshot(Duration),
+  Periodic { delay: Duration, interval: Duration },
+}
+
+/// Non-blocking, close-on-exec timerfd(2).
+#[derive(Debug)]
+pub struct TimerFd {
+  pub fd: RawFd,
+}
+
+unsafe impl Send for TimerFd {}
+
+impl TimerFd {
+  pub fn new(clock: ClockId) -> Result<TimerFd> {
+    let clockid = match clock {
+      ClockId::Realtime => CLOCK_REALTIME,
+      ClockId::Monotonic => CLOCK_MONOTONIC,
+      ClockId::Boottime => CLOCK_BOOTTIME,
+    };
+
+    let fd = unsafe { Errno::result(timerfd_create(clockid, TFD_NONBLOCK | TFD_CLOEXEC))? };
+
+    Ok(TimerFd { fd: fd })
+  }
+
+  pub fn set(&self, spec: TimerSpec) -> Result<()> {
+    let (delay, interval) = match spec {
+      TimerSpec::Oneshot(delay) => (delay, Duration::from_secs(0)),
+      TimerSpec::Periodic { delay, interval } => (delay, interval),
+    };
+
+    // a zero it_value would disarm the timer instead of firing immediately
+    let delay = if delay == Duration::from_secs(0) {
+      Duration::new(0, 1)
+    } else {
+      delay
+    };
+
+    self.settime(delay, interval)
+  }
+
+  pub fn unset(&self) -> Result<()> {
+    self.settime(Duration::from_secs(0), Duration::from_secs(0))
+  }
+
+  /// Number of expirations since the last read, or `None` if the timer has not fired.
+  pub fn read(&self) -> Result<Option<u64>> {
+    let mut buf = [0_u8; 8];
+
+    match syscall!(unistd::read(self.fd, &mut buf))? {
+      Some(8) => Ok(Some(u64::from_ne_bytes(buf))),
+      Some(n) => Err(format!("timerfd: short read of {} bytes", n).into()),
+      None => Ok(None),
+    }
+  }
+
+  fn settime(&self, value: Duration, interval: Duration) -> Result<()> {
+    let spec = itimerspec {
+      it_interval: to_timespec(interval),
+      it_value: to_timespec(value),
+    };
+
+    unsafe {
+      Errno::result(timerfd_settime(self.fd, 0, &spec, ::std::ptr::null_mut()))?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Drop for TimerFd {
+  fn drop(&mut self) {
+    let _ = unistd::close(self.fd);
+  }
+}
+
+fn to_timespec(d: Duration) -> timespec {
+  timespec {
+    tv_sec: d.as_secs() as time_t,
+    tv_nsec: d.subsec_nanos() as c_long,
+  }
+}
+
+/// Identifies a timer of a `Timers` for as long as the `Timers` lives;
+/// never reused, unlike the timerfd number.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimerEvent {
+  pub id: TimerId,
+  pub expirations: u64,
+}
+
+/// Epoll handler that owns a set of timers and delivers their
+/// expirations to `H`.
+///
+/// One-shot timers are released after they fire; periodic timers stay
+/// registered until they are cancelled.
+pub struct Timers<H> {
+  epfd: EpollFd,
+  timers: HashMap<TimerId, (TimerFd, TimerSpec)>,
+  next_id: u64,
+  handler: H,
+}
+
+impl<H> Timers<H>
+  where H: Handler<TimerEvent, EpollCmd>,
+{
+  pub fn new(epfd: EpollFd, handler: H) -> Timers<H> {
+    Timers {
+      epfd: epfd,
+      timers: HashMap::new(),
+      next_id: 0,
+      handler: handler,
+    }
+  }
+
+  pub fn schedule(&mut self, spec: TimerSpec) -> Result<TimerId> {
+    let tfd = TimerFd::new(ClockId::Monotonic)?;
+    let id = TimerId(self.next_id);
+
+    let interest = EpollEvent {
+      events: Self::interests(),
+      data: id.0,
+    };
+
+    self.epfd.register(tfd.fd, &interest)?;
+    tfd.set(spec)?;
+
+    self.next_id += 1;
+    self.timers.insert(id, (tfd, spec));
+
+    Ok(id)
+  }
+
+  pub fn cancel(&mut self, id: TimerId) -> Result<()> {
+    match self.timers.remove(&id) {
+      Some((tfd, _)) => self.epfd.unregister(tfd.fd),
+      None => Ok(()),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.timers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.timers.is_empty()
+  }
+}
+
+impl<H> Handler<EpollEvent, EpollCmd> for Timers<H>
+  where H: Handler<TimerEvent, EpollCmd>,
+{
+  fn next(&mut self) -> EpollCmd {
+    self.handler.next()
+  }
+
+  fn on_next(&mut self, event: EpollEvent) {
+    let id = TimerId(event.data);
+
+    let (expirations, oneshot) = match self.timers.get(&id) {
+      Some(&(ref tfd, spec)) => {
+        match tfd.read() {
+          Ok(Some(n)) => (n, matches!(spec, TimerSpec::Oneshot(_))),
+          Ok(None) => return,
+          Err(e) => {
+            report_err!(e);
+            return;
+          }
+        }
+      }
+      // ignore outstanding event from cancelled timer
+      None => return,
+    };
+
+    if oneshot {
+      if let Err(e) = self.cancel(id) {
+        report_err!(e);
+      }
+    }
+
+    self.handler.on_next(TimerEvent {
+      id: id,
+      expirations: expirations,
+    });
+  }
+
+  fn on_error(&mut self, error: Error) -> Result<()> {
+    self.handler.on_error(error)
+  }
+}
+
+impl<H> EpollHandler for Timers<H> {
+  fn interests() -> EpollEventKind {
+    EPOLLIN
+  }
+
+  fn with_epfd(&mut self, epfd: EpollFd) {
+    self.epfd = epfd;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use epoll::*;
+  use handler::Handler;
+  use std::time::{Duration, Instant};
+  use super::*;
+
+  struct CountHandler {
+    fired: Vec<TimerEvent>,
+  }
+
+  impl Handler<TimerEvent, EpollCmd> for CountHandler {
+    fn next(&mut self) -> EpollCmd {
+      EpollCmd::Poll
+    }
+
+    fn on_next(&mut self, event: TimerEvent) {
+      self.fired.push(event);
+    }
+  }
+
+  fn new_epoll() -> Epoll<Timers<CountHandler>> {
+    let config = EpollConfig {
+      loop_ms: 100,
+      buffer_capacity: 10,
+      ..Default::default()
+    };
+
+    Epoll::new_with(config, |epfd| Timers::new(epfd, CountHandler { fired: Vec::new() })).unwrap()
+  }
+
+  #[test]
+  fn timerfd_oneshot_expires() {
+    let tfd = TimerFd::new(ClockId::Monotonic).unwrap();
+
+    assert_eq!(tfd.read().unwrap(), None);
+
+    let start = Instant::now();
+    tfd.set(TimerSpec::Oneshot(Duration::from_millis(10))).unwrap();
+
+    while tfd.read().unwrap().is_none() {
+      ::std::thread::sleep(Duration::from_millis(1));
+    }
+
+    assert!(start.elapsed() >= Duration::from_millis(10));
+  }
+
+  #[test]
+  fn timerfd_unset_disarms() {
+    let tfd = TimerFd::new(ClockId::Monotonic).unwrap();
+
+    tfd.set(TimerSpec::Oneshot(Duration::from_millis(5))).unwrap();
+    tfd.unset().unwrap();
+
+    ::std::thread::sleep(Duration::from_millis(15));
+
+    assert_eq!(tfd.read().unwrap(), None);
+  }
+
+  #[test]
+  fn delivers_oneshot_expiration() {
+    let mut poll = new_epoll();
+
+    let id = {
+      let timers = poll.handler_mut();
+      timers.schedule(TimerSpec::Oneshot(Duration::from_millis(1))).unwrap()
+    };
+
+    while poll.handler_mut().handler.fired.is_empty() {
+      poll.run_once().unwrap();
+    }
+
+    let timers = poll.handler_mut();
+    assert_eq!(timers.handler.fired, vec![TimerEvent { id: id, expirations: 1 }]);
+    assert!(timers.is_empty());
+  }
+
+  #[test]
+  fn delivers_periodic_expirations_until_cancelled() {
+    let mut poll = new_epoll();
+
+    let spec = TimerSpec::Periodic {
+      delay: Duration::from_millis(1),
+      interval: Duration::from_millis(1),
+    };
+
+    let id = poll.handler_mut().schedule(spec).unwrap();
+
+    let mut total = 0;
+    while total < 3 {
+      poll.run_once().unwrap();
+      total = poll.handler_mut().handler.fired.iter().map(|e| e.expirations).sum();
+    }
+
+    let timers = poll.handler_mut();
+    assert!(timers.handler.fired.iter().all(|e| e.id == id));
+    assert_eq!(timers.len(), 1);
+
+    timers.cancel(id).unwrap();
+    assert!(timers.is_empty());
+  }
+
+  #[test]
+  fn stale_ids_do_not_cancel_new_timers() {
+    let mut poll = new_epoll();
+
+    let old = poll.handler_mut().schedule(TimerSpec::Oneshot(Duration::from_millis(1))).unwrap();
+
+    while poll.handler_mut().handler.fired.is_empty() {
+      poll.run_once().unwrap();
+    }
+
+    // likely gets the timerfd number of the expired timer
+    let spec = TimerSpec::Oneshot(Duration::from_secs(60));
+    let new = poll.handler_mut().schedule(spec).unwrap();
+    assert!(new != old);
+
+    let timers = poll.handler_mut();
+    timers.cancel(old).unwrap();
+    assert_eq!(timers.len(), 1);
+
+    timers.cancel(new).unwrap();
+    assert!(timers.is_empty());
+  }
+
+  /// Records the loop errors it is handed.
+  struct ErrorHandler {
+    errors: Vec<String>,
+  }
+
+  impl Handler<TimerEvent, EpollCmd> for ErrorHandler {
+    fn next(&mut self) -> EpollCmd {
+      EpollCmd::Poll
+    }
+
+    fn on_next(&mut self, _: TimerEvent) {}
+
+    fn on_error(&mut self, error: Error) -> Result<()> {
+      self.errors.push(error.to_string());
+      Err(error)
+    }
+  }
+
+  #[test]
+  fn forwards_loop_errors() {
+    let mut timers = Timers::new(EpollFd::new(-1), ErrorHandler { errors: Vec::new() });
+
+    assert!(timers.on_error("epoll_wait failed".into()).is_err());
+    assert_eq!(timers.handler.errors, vec!["epoll_wait failed"]);
+  }
+}
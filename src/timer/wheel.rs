@@ -0,0 +1,291 @@
+use slab::Slab;
+use std::cmp;
+
+const LEVELS: usize = 4;
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = (SLOTS - 1) as u64;
+
+#[derive(Debug)]
+struct Entry<T> {
+  value: T,
+  deadline: u64,
+  level: usize,
+  slot: usize,
+  prev: Option<usize>,
+  next: Option<usize>,
+}
+
+/// Hierarchical timing wheel.
+///
+/// Time is measured in abstract ticks which the owner advances, typically
+/// from a periodic `TimerFd`. Each of the `LEVELS` wheels has 64 slots and
+/// covers 64 times the range of the previous one; entries are cascaded down
+/// as their level's slot comes around, so insert, cancel and expire are O(1).
+/// Deadlines beyond the range of the outermost wheel are parked in its last
+/// slot and re-inserted on every rotation until they are due.
+#[derive(Debug)]
+pub struct TimingWheel<T> {
+  entries: Slab<Entry<T>, usize>,
+  slots: Vec<[Option<usize>; SLOTS]>,
+  now: u64,
+}
+
+impl<T> TimingWheel<T> {
+  pub fn with_capacity(capacity: usize) -> TimingWheel<T> {
+    TimingWheel {
+      entries: Slab::with_capacity(cmp::max(1, capacity)),
+      slots: vec![[None; SLOTS]; LEVELS],
+      now: 0,
+    }
+  }
+
+  /// Ticks elapsed since the wheel was created.
+  pub fn now(&self) -> u64 {
+    self.now
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Schedule `value` to expire `ticks` from now (at least one tick) and
+  /// return its key.
+  pub fn insert(&mut self, ticks: u64, value: T) -> usize {
+    if !self.entries.has_available() {
+      let additional = self.entries.capacity();
+      self.entries.reserve_exact(additional);
+    }
+
+    let entry = Entry {
+      value: value,
+      deadline: self.now + cmp::max(1, ticks),
+      level: 0,
+      slot: 0,
+      prev: None,
+      next: None,
+    };
+
+    let key = match self.entries.insert(entry) {
+      Ok(key) => key,
+      Err(_) => unreachable!("timing wheel slab has available entries"),
+    };
+
+    self.link(key);
+
+    key
+  }
+
+  pub fn cancel(&mut self, key: usize) -> Option<T> {
+    if !self.entries.contains(key) {
+      return None;
+    }
+
+    self.unlink(key);
+
+    self.entries.remove(key).map(|e| e.value)
+  }
+
+  /// Advance the wheel by `ticks` and push the values that came due into
+  /// `expired`, earliest tick first.
+  pub fn advance(&mut self, ticks: u64, expired: &mut Vec<T>) {
+    let target = self.now + ticks;
+
+    while self.now < target {
+      if self.entries.is_empty() {
+        self.now = target;
+        break;
+      }
+
+      self.now += 1;
+      let now = self.now;
+
+      for level in (1..LEVELS).rev() {
+        if now & ((1 << (SLOT_BITS * level)) - 1) == 0 {
+          let slot = ((now >> (SLOT_BITS * level)) & SLOT_MASK) as usize;
+          self.cascade(level, slot);
+        }
+      }
+
+      let slot = (now & SLOT_MASK) as usize;
+      while let Some(key) = self.slots[0][slot] {
+        self.unlink(key);
+        expired.push(self.entries.remove(key).unwrap().value);
+      }
+    }
+  }
+
+  fn cascade(&mut self, level: usize, slot: usize) {
+    let mut next = self.slots[level][slot].take();
+
+    while let Some(key) = next {
+      next = self.entries[key].next;
+      self.link(key);
+    }
+  }
+
+  fn position(&self, deadline: u64) -> (usize, usize) {
+    if deadline <= self.now {
+      return (0, (self.now & SLOT_MASK) as usize);
+    }
+
+    if deadline - self.now < 1 << (SLOT_BITS * LEVELS) {
+      let level = (63 - (self.now ^ deadline).leading_zeros() as usize) / SLOT_BITS;
+      let level = cmp::min(LEVELS - 1, level);
+      (level, ((deadline >> (SLOT_BITS * level)) & SLOT_MASK) as usize)
+    } else {
+      // out of range: park in the last slot to come around on the outermost wheel
+      let level = LEVELS - 1;
+      let current = (self.now >> (SLOT_BITS * level)) & SLOT_MASK;
+      (level, (current.wrapping_sub(1) & SLOT_MASK) as usize)
+    }
+  }
+
+  fn link(&mut self, key: usize) {
+    let (level, slot) = self.position(self.entries[key].deadline);
+    let head = self.slots[level][slot];
+
+    {
+      let entry = &mut self.entries[key];
+      entry.level = level;
+      entry.slot = slot;
+      entry.prev = None;
+      entry.next = head;
+    }
+
+    if let Some(head) = head {
+      self.entries[head].prev = Some(key);
+    }
+
+    self.slots[level][slot] = Some(key);
+  }
+
+  fn unlink(&mut self, key: usize) {
+    let (level, slot, prev, next) = {
+      let entry = &self.entries[key];
+      (entry.level, entry.slot, entry.prev, entry.next)
+    };
+
+    match prev {
+      Some(prev) => self.entries[prev].next = next,
+      None => self.slots[level][slot] = next,
+    }
+
+    if let Some(next) = next {
+      self.entries[next].prev = prev;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn advance<T>(wheel: &mut TimingWheel<T>, ticks: u64) -> Vec<T> {
+    let mut expired = Vec::new();
+    wheel.advance(ticks, &mut expired);
+    expired
+  }
+
+  #[test]
+  fn expires_on_deadline() {
+    let mut wheel = TimingWheel::with_capacity(4);
+
+    wheel.insert(3, "a");
+    wheel.insert(1, "b");
+
+    assert_eq!(advance(&mut wheel, 1), vec!["b"]);
+    assert_eq!(advance(&mut wheel, 1), Vec::<&str>::new());
+    assert_eq!(advance(&mut wheel, 1), vec!["a"]);
+    assert!(wheel.is_empty());
+  }
+
+  #[test]
+  fn zero_ticks_expire_on_next_tick() {
+    let mut wheel = TimingWheel::with_capacity(1);
+
+    wheel.insert(0, 1);
+
+    assert_eq!(advance(&mut wheel, 0), Vec::<i32>::new());
+    assert_eq!(advance(&mut wheel, 1), vec![1]);
+  }
+
+  #[test]
+  fn cascades_across_levels() {
+    let mut wheel = TimingWheel::with_capacity(4);
+
+    let deadlines = [63, 64, 65, 4095, 4096, 300_000];
+    for d in deadlines.iter() {
+      wheel.insert(*d, *d);
+    }
+
+    let mut now = 0;
+    for d in deadlines.iter() {
+      assert_eq!(advance(&mut wheel, d - 1 - now), Vec::<u64>::new());
+      assert_eq!(advance(&mut wheel, 1), vec![*d]);
+      now = *d;
+    }
+
+    assert!(wheel.is_empty());
+  }
+
+  #[test]
+  fn expires_beyond_wheel_range() {
+    let mut wheel = TimingWheel::with_capacity(1);
+    let range = 1 << (SLOT_BITS * LEVELS);
+
+    wheel.advance(12345, &mut Vec::new());
+    wheel.insert(range + 10, ());
+
+    assert!(advance(&mut wheel, range + 9).is_empty());
+    assert_eq!(advance(&mut wheel, 1).len(), 1);
+  }
+
+  #[test]
+  fn expires_across_wheel_boundary() {
+    let mut wheel = TimingWheel::with_capacity(1);
+    let range = 1 << (SLOT_BITS * LEVELS);
+
+    wheel.advance(range - 3, &mut Vec::new());
+    wheel.insert(10, ());
+
+    assert!(advance(&mut wheel, 9).is_empty());
+    assert_eq!(advance(&mut wheel, 1).len(), 1);
+  }
+
+  #[test]
+  fn cancel_unlinks_entry() {
+    let mut wheel = TimingWheel::with_capacity(1);
+
+    let a = wheel.insert(5, "a");
+    let b = wheel.insert(5, "b");
+    let c = wheel.insert(5, "c");
+
+    assert_eq!(wheel.cancel(b), Some("b"));
+    assert_eq!(wheel.cancel(b), None);
+    assert_eq!(wheel.len(), 2);
+
+    let mut expired = advance(&mut wheel, 5);
+    expired.sort();
+    assert_eq!(expired, vec!["a", "c"]);
+
+    assert_eq!(wheel.cancel(a), None);
+    assert_eq!(wheel.cancel(c), None);
+  }
+
+  #[test]
+  fn grows_capacity() {
+    let mut wheel = TimingWheel::with_capacity(1);
+
+    for i in 0..100 {
+      wheel.insert(i, i);
+    }
+
+    assert_eq!(wheel.len(), 100);
+    assert_eq!(advance(&mut wheel, 100).len(), 100);
+  }
+}